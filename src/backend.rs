@@ -0,0 +1,633 @@
+use crate::config::{CleanupAction, KeyType};
+use crate::connection::connection_manager;
+use crate::key_types::TypeFilter;
+use crate::usage::UsageFilter;
+use redis::aio::ConnectionManager;
+use redis::{Client, RedisResult};
+use std::collections::BTreeMap;
+use std::future::Future;
+
+/// Arguments of a whole keyspace walk executed by [`Backend::expire_script`].
+#[derive(Debug, Clone)]
+pub struct ScriptScan<'a> {
+    pub pattern: &'a str,
+    pub count: i64,
+    pub action: CleanupAction,
+    pub ttl_seconds: i64,
+    /// TTLs above it are lowered to it, `None` leaves the existing TTLs alone.
+    pub max_ttl_seconds: Option<i64>,
+    /// Only the keys it selects are changed.
+    pub usage: Option<UsageFilter>,
+    /// Only the keys of its types are changed.
+    pub types: Option<&'a TypeFilter>,
+    pub dry_run: bool,
+    pub cursor: u64,
+    pub max_iterations: i64,
+}
+
+/// What a [`Backend::expire_script`] walk did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScriptOutcome {
+    pub counts: KeyCounts,
+    pub iterations: i64,
+    /// Where the walk stopped, `0` if it completed.
+    pub cursor: u64,
+}
+
+/// The type of every key selected by a [`TypeFilter`].
+pub type KeyTypes = BTreeMap<Vec<u8>, KeyType>;
+
+/// The number of keys changed by a rule, per kind of change.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyCounts {
+    /// Keys without a TTL that got `ttl_seconds`.
+    pub ttl_added: i64,
+    /// Keys whose TTL was lowered to `max_ttl_seconds`.
+    pub ttl_shortened: i64,
+    pub deleted: i64,
+    /// Keys whose TTL was removed.
+    pub persisted: i64,
+    /// The changed keys per type, only counted for the rules with `types`.
+    pub by_type: BTreeMap<KeyType, i64>,
+}
+
+impl KeyCounts {
+    pub fn count(&mut self, change: KeyChange, keys: usize) {
+        let counter = match change {
+            KeyChange::AddTtl(_) => &mut self.ttl_added,
+            KeyChange::ShortenTtl(_) => &mut self.ttl_shortened,
+            KeyChange::Delete => &mut self.deleted,
+            KeyChange::Persist => &mut self.persisted,
+        };
+        *counter += keys as i64;
+    }
+
+    /// Counts the changed `keys` under their type in `key_types`.
+    pub fn count_types(&mut self, key_types: &KeyTypes, keys: &[Vec<u8>]) {
+        for key_type in keys.iter().filter_map(|key| key_types.get(key)) {
+            *self.by_type.entry(*key_type).or_default() += 1;
+        }
+    }
+
+    pub fn add(&mut self, other: &KeyCounts) {
+        self.ttl_added += other.ttl_added;
+        self.ttl_shortened += other.ttl_shortened;
+        self.deleted += other.deleted;
+        self.persisted += other.persisted;
+        for (key_type, keys) in other.by_type.iter() {
+            *self.by_type.entry(*key_type).or_default() += keys;
+        }
+    }
+
+    pub fn total(&self) -> i64 {
+        self.ttl_added + self.ttl_shortened + self.deleted + self.persisted
+    }
+}
+
+/// What a rule does to a key, see [`key_change`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyChange {
+    /// `EXPIRE` on a key without TTL.
+    AddTtl(i64),
+    /// `EXPIRE` on a key whose TTL is above the cap.
+    ShortenTtl(i64),
+    /// `UNLINK`.
+    Delete,
+    /// `PERSIST` on a key with a TTL.
+    Persist,
+}
+
+impl KeyChange {
+    /// The command of the change, for the logs.
+    pub fn command(&self) -> &'static str {
+        match self {
+            KeyChange::AddTtl(_) | KeyChange::ShortenTtl(_) => "EXPIRE",
+            KeyChange::Delete => "UNLINK",
+            KeyChange::Persist => "PERSIST",
+        }
+    }
+}
+
+/// How a rule changes a key with the given `ttl`, `None` if the key is left alone. The
+/// `delete` action does not look at the TTL.
+pub fn key_change(
+    action: CleanupAction,
+    ttl: i64,
+    ttl_seconds: i64,
+    max_ttl_seconds: Option<i64>,
+) -> Option<KeyChange> {
+    match (action, ttl, max_ttl_seconds) {
+        (CleanupAction::Delete, _, _) => Some(KeyChange::Delete),
+        (CleanupAction::Persist, ttl, _) if ttl >= 0 => Some(KeyChange::Persist),
+        (CleanupAction::Persist, _, _) => None,
+        (CleanupAction::Expire, -1, _) => Some(KeyChange::AddTtl(ttl_seconds)),
+        (CleanupAction::Expire, ttl, Some(max_ttl)) if ttl > max_ttl => {
+            Some(KeyChange::ShortenTtl(max_ttl))
+        }
+        (CleanupAction::Expire, _, _) => None,
+    }
+}
+
+/// Groups the keys of a batch by their change; `ttls` are in the order of `keys`, `None`
+/// for the `delete` action, which does not need them.
+pub fn batch_changes(
+    keys: &[Vec<u8>],
+    ttls: Option<&[i64]>,
+    action: CleanupAction,
+    ttl_seconds: i64,
+    max_ttl_seconds: Option<i64>,
+) -> BTreeMap<KeyChange, Vec<Vec<u8>>> {
+    let mut changes: BTreeMap<KeyChange, Vec<Vec<u8>>> = BTreeMap::new();
+    for (index, key) in keys.iter().enumerate() {
+        let ttl = ttls.map_or(-1, |ttls| ttls[index]);
+        if let Some(change) = key_change(action, ttl, ttl_seconds, max_ttl_seconds) {
+            changes.entry(change).or_default().push(key.clone());
+        }
+    }
+    changes
+}
+
+/// The keyspace operations used by the cleanup engines.
+///
+/// The operations are async, so a rule never blocks a worker thread of the runtime while it
+/// waits for Redis.
+pub trait Backend: Send {
+    /// The address of the node, used in the results.
+    fn address(&self) -> String;
+
+    /// One `SCAN cursor MATCH pattern COUNT count [TYPE key_type]` call, returns the next
+    /// cursor and the keys.
+    fn scan(
+        &mut self,
+        cursor: u64,
+        pattern: &str,
+        count: i64,
+        key_type: Option<KeyType>,
+    ) -> impl Future<Output = RedisResult<(u64, Vec<Vec<u8>>)>> + Send;
+
+    /// `TTL` of every key, in the same order.
+    fn ttl(&mut self, keys: &[Vec<u8>]) -> impl Future<Output = RedisResult<Vec<i64>>> + Send;
+
+    /// `EXPIRE key seconds` for every key.
+    fn expire(
+        &mut self,
+        keys: &[Vec<u8>],
+        seconds: i64,
+    ) -> impl Future<Output = RedisResult<()>> + Send;
+
+    /// `UNLINK key` for every key, the memory is freed in the background by the server.
+    fn unlink(&mut self, keys: &[Vec<u8>]) -> impl Future<Output = RedisResult<()>> + Send;
+
+    /// `PERSIST key` for every key.
+    fn persist(&mut self, keys: &[Vec<u8>]) -> impl Future<Output = RedisResult<()>> + Send;
+
+    /// `OBJECT subcommand key` for every key, e.g. `IDLETIME` or `FREQ`, `None` for the keys
+    /// that do not exist.
+    fn object(
+        &mut self,
+        subcommand: &str,
+        keys: &[Vec<u8>],
+    ) -> impl Future<Output = RedisResult<Vec<Option<i64>>>> + Send;
+
+    /// `TYPE key`, e.g. `string` or `hash` (`none` if the key does not exist).
+    fn key_type(&mut self, key: &[u8]) -> impl Future<Output = RedisResult<String>> + Send;
+
+    /// `TYPE key` for every key, in the same order.
+    fn key_types(
+        &mut self,
+        keys: &[Vec<u8>],
+    ) -> impl Future<Output = RedisResult<Vec<String>>> + Send {
+        async move {
+            let mut types = Vec::with_capacity(keys.len());
+            for key in keys.iter() {
+                types.push(self.key_type(key).await?);
+            }
+            Ok(types)
+        }
+    }
+
+    /// `PING`, its round trip is the latency sampled by the throttling.
+    fn ping(&mut self) -> impl Future<Output = RedisResult<()>> + Send;
+
+    /// `INFO section`, e.g. `default` for the statistics sampled by the throttling or
+    /// `replication` for the replica offsets.
+    fn info(&mut self, section: &str) -> impl Future<Output = RedisResult<String>> + Send;
+
+    /// The keys selected by `usage`, all of them without a filter.
+    fn select_by_usage(
+        &mut self,
+        usage: Option<UsageFilter>,
+        keys: Vec<Vec<u8>>,
+    ) -> impl Future<Output = RedisResult<Vec<Vec<u8>>>> + Send {
+        async move {
+            let usage = match usage {
+                Some(usage) if !keys.is_empty() => usage,
+                _ => return Ok(keys),
+            };
+            let values = self.object(usage.subcommand(), &keys).await?;
+            Ok(keys
+                .into_iter()
+                .zip(values)
+                .filter(|(_, value)| usage.selects(*value))
+                .map(|(key, _)| key)
+                .collect())
+        }
+    }
+
+    /// The keys of the types selected by `types` and the type of each of them, all the keys
+    /// (and no types) without a filter. Under `SCAN ... TYPE` the keys are all of the scanned
+    /// type, otherwise their type is checked with `TYPE`.
+    fn select_by_type(
+        &mut self,
+        types: Option<&TypeFilter>,
+        keys: Vec<Vec<u8>>,
+    ) -> impl Future<Output = RedisResult<(Vec<Vec<u8>>, KeyTypes)>> + Send {
+        async move {
+            let types = match types {
+                Some(types) => types,
+                None => return Ok((keys, BTreeMap::new())),
+            };
+            if let Some(scan_type) = types.scan_type() {
+                let key_types = keys.iter().map(|key| (key.clone(), scan_type)).collect();
+                return Ok((keys, key_types));
+            }
+            let names = match keys.is_empty() {
+                true => Vec::new(),
+                false => self.key_types(&keys).await?,
+            };
+            let key_types: KeyTypes = keys
+                .into_iter()
+                .zip(names)
+                .filter_map(|(key, name)| Some((key, types.selects(&name)?)))
+                .collect();
+            Ok((key_types.keys().cloned().collect(), key_types))
+        }
+    }
+
+    /// Applies `change` to every key.
+    fn apply(
+        &mut self,
+        change: KeyChange,
+        keys: &[Vec<u8>],
+    ) -> impl Future<Output = RedisResult<()>> + Send {
+        async move {
+            match change {
+                KeyChange::AddTtl(seconds) | KeyChange::ShortenTtl(seconds) => {
+                    self.expire(keys, seconds).await
+                }
+                KeyChange::Delete => self.unlink(keys).await,
+                KeyChange::Persist => self.persist(keys).await,
+            }
+        }
+    }
+
+    /// Walks the keyspace from `scan.cursor` and applies the action of the rule to every
+    /// matching key: for `expire` it sets the TTL of the keys without one and lowers the
+    /// TTLs above `scan.max_ttl_seconds`.
+    ///
+    /// The default implementation drives the walk with the other operations, backends that
+    /// can run it server-side (see [`RedisBackend`]) override it.
+    fn expire_script(
+        &mut self,
+        scan: &ScriptScan,
+    ) -> impl Future<Output = RedisResult<ScriptOutcome>> + Send {
+        async move {
+            let mut outcome = ScriptOutcome {
+                cursor: scan.cursor,
+                ..ScriptOutcome::default()
+            };
+            loop {
+                outcome.iterations += 1;
+                let scan_type = scan.types.and_then(|types| types.scan_type());
+                let (next_cursor, keys) = self
+                    .scan(outcome.cursor, scan.pattern, scan.count, scan_type)
+                    .await?;
+                let (keys, key_types) = self.select_by_type(scan.types, keys).await?;
+                let keys = self.select_by_usage(scan.usage, keys).await?;
+                let ttls = match scan.action {
+                    CleanupAction::Delete => None,
+                    _ => Some(self.ttl(&keys).await?),
+                };
+                let changes = batch_changes(
+                    &keys,
+                    ttls.as_deref(),
+                    scan.action,
+                    scan.ttl_seconds,
+                    scan.max_ttl_seconds,
+                );
+                for (change, keys) in changes {
+                    outcome.counts.count(change, keys.len());
+                    outcome.counts.count_types(&key_types, &keys);
+                    if !scan.dry_run {
+                        self.apply(change, &keys).await?;
+                    }
+                }
+                outcome.cursor = next_cursor;
+                if outcome.cursor == 0 || outcome.iterations >= scan.max_iterations {
+                    return Ok(outcome);
+                }
+            }
+        }
+    }
+}
+
+/// [`Backend`] over a single Redis server (or a single cluster node), on a multiplexed
+/// [`ConnectionManager`].
+pub struct RedisBackend {
+    address: String,
+    connection: ConnectionManager,
+    cluster: bool,
+}
+
+impl RedisBackend {
+    pub async fn connect(
+        address: String,
+        client: &Client,
+        cluster: bool,
+    ) -> RedisResult<RedisBackend> {
+        Ok(RedisBackend {
+            address,
+            connection: connection_manager(client).await?,
+            cluster,
+        })
+    }
+}
+
+const LUA_SCRIPT: &str = r###"
+	local match = ARGV[1];
+	local count = tonumber(ARGV[2]);
+	local expire_num = tonumber(ARGV[3]);
+	local dry_run = tonumber(ARGV[4]);
+	local iterations = 0;
+	local max_iterations = tonumber(ARGV[6]);
+	local processed = 0;
+	local cursor = ARGV[5];
+	local max_ttl = tonumber(ARGV[7]);
+	local shortened = 0;
+	local action = ARGV[8];
+	local deleted = 0;
+	local persisted = 0;
+	local usage = ARGV[9];
+	local usage_limit = tonumber(ARGV[10]);
+	local scan_type = ARGV[11];
+	local check_types = nil;
+	if ARGV[12] ~= "" then
+		check_types = {};
+		for t in string.gmatch(ARGV[12], "[^,]+") do
+			check_types[t] = true;
+		end
+	end
+	local by_type = {};
+	local key_type = nil;
+	local function count_type()
+		if key_type then
+			by_type[key_type] = (by_type[key_type] or 0) + 1;
+		end
+	end
+	repeat
+		iterations = iterations + 1;
+		local result;
+		if scan_type ~= "" then
+			result = redis.call("SCAN", cursor, "MATCH", match, "COUNT", count, "TYPE", scan_type);
+		else
+			result = redis.call("SCAN", cursor, "MATCH", match, "COUNT", count);
+		end
+		for _, v in ipairs(result[2]) do
+			local selected = true;
+			key_type = nil;
+			if scan_type ~= "" then
+				key_type = scan_type;
+			elseif check_types then
+				key_type = redis.call("TYPE", v)["ok"];
+				selected = check_types[key_type] == true;
+			end
+			if not selected then
+				-- another type, left alone
+			elseif usage == "IDLETIME" then
+				local idle = redis.call("OBJECT", "IDLETIME", v);
+				selected = idle ~= false and idle >= usage_limit;
+			elseif usage == "FREQ" then
+				local freq = redis.call("OBJECT", "FREQ", v);
+				selected = freq ~= false and freq <= usage_limit;
+			end
+			local ttl = -1;
+			if selected and action ~= "delete" then
+				ttl = redis.call("TTL", v);
+			end
+			if not selected then
+				-- used too recently or too often, left alone
+			elseif action == "delete" then
+				deleted = deleted + 1;
+				count_type();
+				if dry_run == 0 then
+					redis.call("UNLINK", v);
+				end
+			elseif action == "persist" then
+				if ttl >= 0 then
+					persisted = persisted + 1;
+					count_type();
+					if dry_run == 0 then
+						redis.call("PERSIST", v);
+					end
+				end
+			elseif ttl == -1 then
+				processed = processed + 1;
+				count_type();
+				if dry_run == 0 then
+        			redis.call("EXPIRE", v, expire_num);
+				end
+			elseif max_ttl >= 0 and ttl > max_ttl then
+				shortened = shortened + 1;
+				count_type();
+				if dry_run == 0 then
+					redis.call("EXPIRE", v, max_ttl);
+				end
+			end
+		end
+		cursor = result[1];
+	until cursor == "0" or iterations >= max_iterations;
+	local types = {};
+	for t, n in pairs(by_type) do
+		table.insert(types, t);
+		table.insert(types, n);
+	end
+	local ret = {processed, iterations, cursor, shortened, deleted, persisted, types}
+	return ret"###;
+
+impl Backend for RedisBackend {
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+
+    async fn scan(
+        &mut self,
+        cursor: u64,
+        pattern: &str,
+        count: i64,
+        key_type: Option<KeyType>,
+    ) -> RedisResult<(u64, Vec<Vec<u8>>)> {
+        let mut cmd = redis::cmd("SCAN");
+        cmd.arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(count);
+        if let Some(key_type) = key_type {
+            cmd.arg("TYPE").arg(key_type.as_str());
+        }
+        cmd.query_async(&mut self.connection).await
+    }
+
+    async fn ttl(&mut self, keys: &[Vec<u8>]) -> RedisResult<Vec<i64>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        for key in keys.iter() {
+            pipe.cmd("TTL").arg(key);
+        }
+        pipe.query_async(&mut self.connection).await
+    }
+
+    async fn expire(&mut self, keys: &[Vec<u8>], seconds: i64) -> RedisResult<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for key in keys.iter() {
+            pipe.cmd("EXPIRE").arg(key).arg(seconds).ignore();
+        }
+        pipe.query_async(&mut self.connection).await
+    }
+
+    async fn unlink(&mut self, keys: &[Vec<u8>]) -> RedisResult<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        // One command per key, the keys of a cluster node belong to different hash slots.
+        let mut pipe = redis::pipe();
+        for key in keys.iter() {
+            pipe.cmd("UNLINK").arg(key).ignore();
+        }
+        pipe.query_async(&mut self.connection).await
+    }
+
+    async fn persist(&mut self, keys: &[Vec<u8>]) -> RedisResult<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for key in keys.iter() {
+            pipe.cmd("PERSIST").arg(key).ignore();
+        }
+        pipe.query_async(&mut self.connection).await
+    }
+
+    async fn object(
+        &mut self,
+        subcommand: &str,
+        keys: &[Vec<u8>],
+    ) -> RedisResult<Vec<Option<i64>>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        for key in keys.iter() {
+            pipe.cmd("OBJECT").arg(subcommand).arg(key);
+        }
+        pipe.query_async(&mut self.connection).await
+    }
+
+    async fn key_type(&mut self, key: &[u8]) -> RedisResult<String> {
+        redis::cmd("TYPE")
+            .arg(key)
+            .query_async(&mut self.connection)
+            .await
+    }
+
+    async fn key_types(&mut self, keys: &[Vec<u8>]) -> RedisResult<Vec<String>> {
+        let mut pipe = redis::pipe();
+        for key in keys.iter() {
+            pipe.cmd("TYPE").arg(key);
+        }
+        pipe.query_async(&mut self.connection).await
+    }
+
+    async fn ping(&mut self) -> RedisResult<()> {
+        redis::cmd("PING")
+            .query_async::<String>(&mut self.connection)
+            .await
+            .map(|_| ())
+    }
+
+    async fn info(&mut self, section: &str) -> RedisResult<String> {
+        redis::cmd("INFO")
+            .arg(section)
+            .query_async(&mut self.connection)
+            .await
+    }
+
+    async fn expire_script(&mut self, scan: &ScriptScan<'_>) -> RedisResult<ScriptOutcome> {
+        let script = redis::Script::new(LUA_SCRIPT);
+        let dry_run_num = match scan.dry_run {
+            true => 1,
+            false => 0,
+        };
+        // With a single type on a recent server SCAN leaves out the other types, otherwise
+        // the script checks the type of every key.
+        let scan_type = scan.types.and_then(|types| types.scan_type());
+        let check_types = match scan.types {
+            Some(types) if scan_type.is_none() => types
+                .types()
+                .iter()
+                .map(|key_type| key_type.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => String::new(),
+        };
+        let mut invocation = script.prepare_invoke();
+        // On a cluster node the pattern must not be declared as a key, otherwise the node
+        // answers with MOVED for every pattern whose hash slot it does not own.
+        if !self.cluster {
+            invocation.key(scan.pattern);
+        }
+        invocation
+            .arg(scan.pattern)
+            .arg(scan.count)
+            .arg(scan.ttl_seconds)
+            .arg(dry_run_num)
+            .arg(scan.cursor)
+            .arg(scan.max_iterations)
+            // -1 disables the cap, a TTL is never below -1 for an existing key
+            .arg(scan.max_ttl_seconds.unwrap_or(-1))
+            .arg(scan.action.as_str())
+            .arg(scan.usage.map_or("", |usage| usage.subcommand()))
+            .arg(match scan.usage {
+                Some(UsageFilter::MinIdle(limit) | UsageFilter::MaxFrequency(limit)) => limit,
+                None => 0,
+            })
+            .arg(scan_type.map_or("", |key_type| key_type.as_str()))
+            .arg(check_types)
+            .invoke_async(&mut self.connection)
+            .await
+            .map(
+                |(ttl_added, iterations, cursor, ttl_shortened, deleted, persisted, types)| {
+                    let types: BTreeMap<String, i64> = types;
+                    ScriptOutcome {
+                        counts: KeyCounts {
+                            ttl_added,
+                            ttl_shortened,
+                            deleted,
+                            persisted,
+                            by_type: types
+                                .into_iter()
+                                .filter_map(|(name, keys)| Some((KeyType::from_name(&name)?, keys)))
+                                .collect(),
+                        },
+                        iterations,
+                        cursor,
+                    }
+                },
+            )
+    }
+}
@@ -0,0 +1,144 @@
+use crate::connection::RedisConnection;
+use crate::error::CleanerError;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Location where the SCAN cursor of every rule is persisted between batches and runs.
+#[derive(Debug, Clone)]
+pub enum CheckpointStore {
+    Disabled,
+    File { path: PathBuf, lock: Arc<Mutex<()>> },
+    Redis { prefix: String },
+}
+
+impl CheckpointStore {
+    pub fn file(path: &str) -> CheckpointStore {
+        CheckpointStore::File {
+            path: PathBuf::from(path),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn redis(prefix: &str) -> CheckpointStore {
+        CheckpointStore::Redis {
+            prefix: prefix.to_string(),
+        }
+    }
+
+    pub fn is_redis(&self) -> bool {
+        matches!(self, CheckpointStore::Redis { .. })
+    }
+}
+
+/// The checkpoint of one rule (on one node in cluster mode).
+pub struct Checkpoint {
+    store: CheckpointStore,
+    id: String,
+    connection: Option<RedisConnection>,
+}
+
+impl Checkpoint {
+    /// `connection` is only used (and required) by the Redis store.
+    pub fn new(
+        store: &CheckpointStore,
+        id: String,
+        connection: Option<RedisConnection>,
+    ) -> Checkpoint {
+        Checkpoint {
+            store: store.clone(),
+            id,
+            connection,
+        }
+    }
+
+    /// Returns the persisted cursor, `0` if the rule has no unfinished scan.
+    pub async fn load(&mut self) -> Result<u64, CleanerError> {
+        match &self.store {
+            CheckpointStore::Disabled => Ok(0),
+            CheckpointStore::File { path, lock } => {
+                let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+                Ok(read_state(path)?.get(&self.id).copied().unwrap_or(0))
+            }
+            CheckpointStore::Redis { prefix } => {
+                let key = format!("{}{}", prefix, self.id);
+                let connection = self.redis_connection()?;
+                let cursor: Option<u64> = redis::cmd("GET")
+                    .arg(key)
+                    .query_async(connection)
+                    .await
+                    .map_err(checkpoint_error)?;
+                Ok(cursor.unwrap_or(0))
+            }
+        }
+    }
+
+    /// Persists `cursor`; a `0` cursor means the scan completed and clears the checkpoint.
+    pub async fn save(&mut self, cursor: u64) -> Result<(), CleanerError> {
+        match &self.store {
+            CheckpointStore::Disabled => Ok(()),
+            CheckpointStore::File { path, lock } => {
+                let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+                let mut state = read_state(path)?;
+                if cursor == 0 {
+                    state.remove(&self.id);
+                } else {
+                    state.insert(self.id.clone(), cursor);
+                }
+                write_state(path, &state)
+            }
+            CheckpointStore::Redis { prefix } => {
+                let key = format!("{}{}", prefix, self.id);
+                let connection = self.redis_connection()?;
+                if cursor == 0 {
+                    redis::cmd("DEL")
+                        .arg(key)
+                        .query_async::<()>(connection)
+                        .await
+                        .map_err(checkpoint_error)?;
+                } else {
+                    redis::cmd("SET")
+                        .arg(key)
+                        .arg(cursor)
+                        .query_async::<()>(connection)
+                        .await
+                        .map_err(checkpoint_error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn redis_connection(&mut self) -> Result<&mut RedisConnection, CleanerError> {
+        match self.connection.as_mut() {
+            Some(connection) => Ok(connection),
+            None => Err(checkpoint_error(
+                "no Redis connection available for the checkpoint store",
+            )),
+        }
+    }
+}
+
+fn checkpoint_error(err: impl Display) -> CleanerError {
+    CleanerError::Checkpoint(err.to_string())
+}
+
+fn read_state(path: &Path) -> Result<BTreeMap<String, u64>, CleanerError> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content = std::fs::read_to_string(path).map_err(checkpoint_error)?;
+    if content.trim().is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    serde_json::from_str(&content).map_err(checkpoint_error)
+}
+
+fn write_state(path: &Path, state: &BTreeMap<String, u64>) -> Result<(), CleanerError> {
+    // Write to a sibling file first, so a killed process never leaves a truncated state file.
+    let tmp_path = path.with_extension("tmp");
+    let content = serde_json::to_string_pretty(state).map_err(checkpoint_error)?;
+    std::fs::write(&tmp_path, content).map_err(checkpoint_error)?;
+    std::fs::rename(tmp_path, path).map_err(checkpoint_error)
+}
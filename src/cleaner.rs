@@ -0,0 +1,1806 @@
+use crate::backend::{batch_changes, Backend, KeyCounts, KeyTypes, RedisBackend, ScriptScan};
+use crate::checkpoint::{Checkpoint, CheckpointStore};
+use crate::config::{CleanupAction, CleanupConfig, CleanupEngine, RedisOptions};
+use crate::connection::{
+    check_connection, create_redis_client, discover_cluster_primaries, open_connection,
+    resolve_primary,
+};
+use crate::error::{CleanerError, ErrorCategory};
+use crate::key_types::TypeFilter;
+use crate::lock::{LockGuard, RunLock};
+use crate::matcher::KeyMatcher;
+use crate::rate::{RateLimit, RateLimiter};
+use crate::redact::redact;
+use crate::result::{IncompleteReason, NodeResult, ProcessingResult, ProcessingStatus, Timings};
+use crate::retry::RetryPolicy;
+use crate::schedule::RuleSchedule;
+use crate::throttle::{replica_lag_bytes, Health, Throttle};
+use crate::usage::{maxmemory_policy, UsageFilter};
+use chrono::Utc;
+use log::{error, info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Default)]
+struct ScanProgress {
+    counts: KeyCounts,
+    /// Keys left out by the exclude globs and the regex.
+    excluded: i64,
+    iterations: i64,
+    resumed: bool,
+    completed: bool,
+    /// Why the scan stopped before `completed`.
+    stopped: Option<IncompleteReason>,
+    connect_time: Duration,
+    scan_time: Duration,
+    expire_time: Duration,
+    /// Pauses of the rate limits, the throttling and the waits for replicas.
+    throttle_time: Duration,
+    retries: u32,
+}
+
+/// Awaits a backend operation with the retry policy; the connection manager of the backend
+/// reconnects by itself, so a retried operation runs on a new connection if the old one broke.
+macro_rules! retrying {
+    ($retry:expr, $retries:expr, $what:expr, $operation:expr) => {{
+        let mut attempts = $retry.attempts($what);
+        loop {
+            match $operation.await {
+                Ok(value) => break Ok(value),
+                Err(err) => {
+                    if let Err(err) = attempts.failed(err, $retries).await {
+                        break Err(err);
+                    }
+                }
+            }
+        }
+    }};
+}
+
+async fn open_checkpoint(
+    options: &RedisOptions,
+    store: &CheckpointStore,
+    id: String,
+) -> Result<Checkpoint, CleanerError> {
+    if !store.is_redis() {
+        return Ok(Checkpoint::new(store, id, None));
+    }
+    let connection = open_connection(options).await?;
+    Ok(Checkpoint::new(store, id, Some(connection)))
+}
+
+/// Which of the keys matched by the pattern a rule changes on a node.
+#[derive(Debug)]
+struct KeyFilters {
+    matcher: KeyMatcher,
+    usage: Option<UsageFilter>,
+    types: Option<TypeFilter>,
+}
+
+impl KeyFilters {
+    /// Whether the keys are selected by their pattern alone, without `TYPE` or `OBJECT`.
+    fn is_empty(&self) -> bool {
+        self.usage.is_none() && self.types.is_none()
+    }
+}
+
+/// How a rule runs, besides its configuration: the same for every node of the rule.
+struct RuleRun<'a> {
+    retry: &'a RetryPolicy,
+    limiter: &'a RateLimiter,
+    dry_run: bool,
+    /// The lock of the rule, checked between the batches.
+    lock: &'a LockGuard,
+}
+
+async fn expire_keys<B: Backend>(
+    backend: &mut B,
+    run: &RuleRun<'_>,
+    conf: &CleanupConfig,
+    checkpoint: &mut Checkpoint,
+) -> (Option<CleanerError>, ScanProgress) {
+    let mut progress = ScanProgress::default();
+    if let Err(err) = conf.check_max_ttl() {
+        return (Some(err), progress);
+    }
+    let cursor = match checkpoint.load().await {
+        Ok(cursor) => cursor,
+        Err(err) => return (Some(err), progress),
+    };
+    if cursor != 0 {
+        info!("{} - Resuming scan from cursor {}", conf.name, cursor);
+        progress.resumed = true;
+    }
+    let matcher = match KeyMatcher::for_config(conf) {
+        Ok(matcher) => matcher,
+        Err(err) => return (Some(err), progress),
+    };
+    let usage = match usage_filter(backend, run, conf, &mut progress).await {
+        Ok(usage) => usage,
+        Err(err) => return (Some(err), progress),
+    };
+    let types = match type_filter(backend, run, conf, &mut progress).await {
+        Ok(types) => types,
+        Err(err) => return (Some(err), progress),
+    };
+    let filters = KeyFilters {
+        matcher,
+        usage,
+        types,
+    };
+    let result = match conf.engine {
+        CleanupEngine::Lua => {
+            expire_keys_lua(
+                backend,
+                run,
+                conf,
+                &filters,
+                cursor,
+                checkpoint,
+                &mut progress,
+            )
+            .await
+        }
+        CleanupEngine::Scan => {
+            expire_keys_scan(
+                backend,
+                run,
+                conf,
+                &filters,
+                cursor,
+                checkpoint,
+                &mut progress,
+            )
+            .await
+        }
+    };
+    (result.err(), progress)
+}
+
+/// The usage filter of the rule, checked against the eviction policy of the node: under the
+/// wrong policy `OBJECT` fails (or reports a meaningless value) on every key, so the rule
+/// fails before touching any.
+async fn usage_filter<B: Backend>(
+    backend: &mut B,
+    run: &RuleRun<'_>,
+    conf: &CleanupConfig,
+    progress: &mut ScanProgress,
+) -> Result<Option<UsageFilter>, CleanerError> {
+    let usage = match UsageFilter::for_config(conf)? {
+        Some(usage) => usage,
+        None => return Ok(None),
+    };
+    let info: String = retrying!(
+        run.retry,
+        &mut progress.retries,
+        "INFO",
+        backend.info("memory")
+    )?;
+    // Servers without the field (or proxies hiding it) are left to fail on OBJECT.
+    if let Some(policy) = maxmemory_policy(&info) {
+        usage
+            .check_policy(policy)
+            .map_err(|msg| CleanerError::Config(format!("rule '{}': {}", conf.name, msg)))?;
+    }
+    Ok(Some(usage))
+}
+
+/// The type filter of the rule, `SCAN ... TYPE` is used when the version of the node has it.
+async fn type_filter<B: Backend>(
+    backend: &mut B,
+    run: &RuleRun<'_>,
+    conf: &CleanupConfig,
+    progress: &mut ScanProgress,
+) -> Result<Option<TypeFilter>, CleanerError> {
+    if conf.types.is_empty() {
+        return Ok(None);
+    }
+    let info: String = retrying!(
+        run.retry,
+        &mut progress.retries,
+        "INFO",
+        backend.info("server")
+    )?;
+    Ok(TypeFilter::for_config(conf, &info))
+}
+
+async fn expire_keys_lua<B: Backend>(
+    backend: &mut B,
+    run: &RuleRun<'_>,
+    conf: &CleanupConfig,
+    filters: &KeyFilters,
+    mut cursor: u64,
+    checkpoint: &mut Checkpoint,
+    progress: &mut ScanProgress,
+) -> Result<(), CleanerError> {
+    // The script can not pause between its batches, so under a rate limit, throttling or
+    // replica lag limit every call only walks a single batch and the pause comes before the
+    // next call.
+    let limited =
+        run.limiter.is_limited() || conf.throttle.is_some() || conf.max_replica_lag_bytes.is_some();
+    let mut throttle = conf.throttle.as_ref().map(Throttle::new);
+    loop {
+        if run.lock.is_lost() {
+            progress.stopped = Some(IncompleteReason::LockLost);
+            break;
+        }
+        if limited {
+            if !wait_for_health(backend, run, conf, &mut throttle, progress).await?
+                || !wait_for_replicas(backend, run, conf, progress).await?
+            {
+                break;
+            }
+            progress.throttle_time += run.limiter.scan().await;
+            progress.throttle_time += run.limiter.keys(conf.batch.max(1) as usize).await;
+        }
+        // SCAN, TTL and EXPIRE all happen inside the script, so its whole run counts as scan
+        // time.
+        let scan_start = Instant::now();
+        let scan = ScriptScan {
+            pattern: &conf.pattern,
+            count: conf.batch,
+            action: conf.action,
+            ttl_seconds: conf.ttl_seconds,
+            max_ttl_seconds: conf.max_ttl_seconds,
+            usage: filters.usage,
+            types: filters.types.as_ref(),
+            dry_run: run.dry_run,
+            cursor,
+            max_iterations: match limited {
+                true => 1,
+                false => conf.max_iterations - progress.iterations,
+            },
+        };
+        let script_result = retrying!(
+            run.retry,
+            &mut progress.retries,
+            "Script",
+            backend.expire_script(&scan)
+        );
+        progress.scan_time += scan_start.elapsed();
+        let outcome = script_result?;
+        progress.counts.add(&outcome.counts);
+        progress.iterations += outcome.iterations;
+        cursor = outcome.cursor;
+        // A call is a single server-side walk, so the checkpoint is stored once it returns.
+        checkpoint.save(cursor).await?;
+        if cursor == 0 {
+            progress.completed = true;
+            break;
+        }
+        if progress.iterations >= conf.max_iterations {
+            progress.stopped = Some(IncompleteReason::MaxIterations);
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn expire_keys_scan<B: Backend>(
+    backend: &mut B,
+    run: &RuleRun<'_>,
+    conf: &CleanupConfig,
+    filters: &KeyFilters,
+    mut cursor: u64,
+    checkpoint: &mut Checkpoint,
+    progress: &mut ScanProgress,
+) -> Result<(), CleanerError> {
+    let scan_type = filters.types.as_ref().and_then(|types| types.scan_type());
+    let mut throttle = conf.throttle.as_ref().map(Throttle::new);
+    loop {
+        if run.lock.is_lost() {
+            progress.stopped = Some(IncompleteReason::LockLost);
+            break;
+        }
+        if !wait_for_health(backend, run, conf, &mut throttle, progress).await?
+            || !wait_for_replicas(backend, run, conf, progress).await?
+        {
+            break;
+        }
+        progress.iterations += 1;
+        progress.throttle_time += run.limiter.scan().await;
+        let scan_start = Instant::now();
+        let scanned = retrying!(
+            run.retry,
+            &mut progress.retries,
+            "SCAN",
+            backend.scan(
+                cursor,
+                filters.matcher.scan_pattern(),
+                conf.batch,
+                scan_type
+            )
+        );
+        progress.scan_time += scan_start.elapsed();
+        let (next_cursor, keys) = scanned?;
+        let (keys, excluded) = filters.matcher.select(keys);
+        progress.excluded += excluded;
+        // Under a key rate limit the keys of a batch are sent in smaller pipelines.
+        let chunk = run.limiter.key_chunk().unwrap_or(keys.len()).max(1);
+        for keys in keys.chunks(chunk) {
+            progress.throttle_time += run.limiter.keys(keys.len()).await;
+            let (selected, key_types) = match filters.is_empty() {
+                true => (None, BTreeMap::new()),
+                false => {
+                    let (selected, key_types) =
+                        select_keys(backend, run, filters, keys, progress).await?;
+                    (Some(selected), key_types)
+                }
+            };
+            let keys = selected.as_deref().unwrap_or(keys);
+            // UNLINK does not depend on the TTL, the delete action skips the TTL calls.
+            let ttls = match conf.action {
+                CleanupAction::Delete => None,
+                _ => {
+                    let ttl_start = Instant::now();
+                    let ttls =
+                        retrying!(run.retry, &mut progress.retries, "TTL", backend.ttl(keys));
+                    progress.scan_time += ttl_start.elapsed();
+                    Some(ttls?)
+                }
+            };
+            let changes = batch_changes(
+                keys,
+                ttls.as_deref(),
+                conf.action,
+                conf.ttl_seconds,
+                conf.max_ttl_seconds,
+            );
+            for (change, keys) in changes {
+                progress.counts.count(change, keys.len());
+                progress.counts.count_types(&key_types, &keys);
+                if run.dry_run {
+                    continue;
+                }
+                let expire_start = Instant::now();
+                let applied = retrying!(
+                    run.retry,
+                    &mut progress.retries,
+                    change.command(),
+                    backend.apply(change, &keys)
+                );
+                progress.expire_time += expire_start.elapsed();
+                applied?;
+            }
+        }
+        cursor = next_cursor;
+        checkpoint.save(cursor).await?;
+        if cursor == 0 {
+            progress.completed = true;
+            break;
+        }
+        if progress.iterations >= conf.max_iterations {
+            progress.stopped = Some(IncompleteReason::MaxIterations);
+            break;
+        }
+        // Give the server (and other clients) room between batches.
+        if conf.batch_pause_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(conf.batch_pause_ms)).await;
+        }
+    }
+    Ok(())
+}
+
+/// The keys of the types of the rule, used long enough ago (or rarely enough), which go on
+/// to TTL and EXPIRE, with their types.
+async fn select_keys<B: Backend>(
+    backend: &mut B,
+    run: &RuleRun<'_>,
+    filters: &KeyFilters,
+    keys: &[Vec<u8>],
+    progress: &mut ScanProgress,
+) -> Result<(Vec<Vec<u8>>, KeyTypes), CleanerError> {
+    let select_start = Instant::now();
+    let typed = retrying!(
+        run.retry,
+        &mut progress.retries,
+        "TYPE",
+        backend.select_by_type(filters.types.as_ref(), keys.to_vec())
+    );
+    let selected = match typed {
+        Ok((keys, key_types)) => retrying!(
+            run.retry,
+            &mut progress.retries,
+            "OBJECT",
+            backend.select_by_usage(filters.usage, keys.clone())
+        )
+        .map(|keys| (keys, key_types)),
+        Err(err) => Err(err),
+    };
+    progress.scan_time += select_start.elapsed();
+    Ok(selected?)
+}
+
+/// Samples the health of the node when it is due, and pauses while it is overloaded. Returns
+/// `false` if the node is still overloaded after the `maxWaitMs` of the throttle, the rule
+/// stops there.
+async fn wait_for_health<B: Backend>(
+    backend: &mut B,
+    run: &RuleRun<'_>,
+    conf: &CleanupConfig,
+    throttle: &mut Option<Throttle<'_>>,
+    progress: &mut ScanProgress,
+) -> Result<bool, CleanerError> {
+    let (throttle, max_wait) = match (throttle, &conf.throttle) {
+        (Some(throttle), Some(config)) => (throttle, Duration::from_millis(config.max_wait_ms)),
+        _ => return Ok(true),
+    };
+    let wait_start = Instant::now();
+    while throttle.is_due(Instant::now()) {
+        let ping_start = Instant::now();
+        retrying!(run.retry, &mut progress.retries, "PING", backend.ping())?;
+        let latency = ping_start.elapsed();
+        let info = retrying!(
+            run.retry,
+            &mut progress.retries,
+            "INFO",
+            backend.info("default")
+        )?;
+        let (reason, pause) = match throttle.observe(&Health::parse(&info, latency), Instant::now())
+        {
+            Some(overloaded) => overloaded,
+            None => break,
+        };
+        let waited = wait_start.elapsed();
+        if waited >= max_wait {
+            warn!(
+                "{} - {} on {} after {:?}, stopping the rule",
+                conf.name,
+                reason,
+                backend.address(),
+                waited
+            );
+            progress.stopped = Some(IncompleteReason::ThrottleWaitExceeded);
+            return Ok(false);
+        }
+        info!(
+            "{} - {} on {}, pausing for {:?}",
+            conf.name,
+            reason,
+            backend.address(),
+            pause
+        );
+        tokio::time::sleep(pause).await;
+        progress.throttle_time += pause;
+    }
+    Ok(true)
+}
+
+/// Holds the next batch until the replicas of the node caught up with `maxReplicaLagBytes`,
+/// so the writes of the rule never pile up into stale reads on the replicas. Returns `false`
+/// if the replicas are still behind after `maxReplicaWaitMs`, the rule stops there.
+async fn wait_for_replicas<B: Backend>(
+    backend: &mut B,
+    run: &RuleRun<'_>,
+    conf: &CleanupConfig,
+    progress: &mut ScanProgress,
+) -> Result<bool, CleanerError> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+    let max_lag = match conf.max_replica_lag_bytes {
+        Some(max_lag) => max_lag,
+        None => return Ok(true),
+    };
+    let max_wait = Duration::from_millis(conf.max_replica_wait_ms);
+    let wait_start = Instant::now();
+    let mut waiting = false;
+    loop {
+        let info = retrying!(
+            run.retry,
+            &mut progress.retries,
+            "INFO replication",
+            backend.info("replication")
+        )?;
+        let lag = match replica_lag_bytes(&info) {
+            Some(lag) if lag > max_lag => lag,
+            _ => return Ok(true),
+        };
+        let waited = wait_start.elapsed();
+        if waited >= max_wait {
+            warn!(
+                "{} - Replicas of {} are still {} bytes behind after {:?}, stopping the rule",
+                conf.name,
+                backend.address(),
+                lag,
+                waited
+            );
+            progress.stopped = Some(IncompleteReason::ReplicaWaitExceeded);
+            return Ok(false);
+        }
+        if !waiting {
+            info!(
+                "{} - Replicas of {} are {} bytes behind, waiting for them",
+                conf.name,
+                backend.address(),
+                lag
+            );
+            waiting = true;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+        progress.throttle_time += POLL_INTERVAL;
+    }
+}
+
+async fn expire_keys_cluster(
+    options: &RedisOptions,
+    store: &CheckpointStore,
+    run: &RuleRun<'_>,
+    conf: &CleanupConfig,
+) -> Vec<NodeResult> {
+    const MAX_TOPOLOGY_REFRESHES: usize = 3;
+    let mut results: Vec<NodeResult> = Vec::new();
+    let mut done: BTreeSet<String> = BTreeSet::new();
+    for attempt in 0..=MAX_TOPOLOGY_REFRESHES {
+        let discovery_start = Instant::now();
+        let mut retries = 0;
+        let discovered = run
+            .retry
+            .run("Cluster discovery", &mut retries, || {
+                discover_cluster_primaries(options)
+            })
+            .await;
+        let nodes = match discovered {
+            Ok(nodes) => nodes,
+            Err(err) => {
+                let progress = ScanProgress {
+                    connect_time: discovery_start.elapsed(),
+                    retries,
+                    ..ScanProgress::default()
+                };
+                results.push(node_result(
+                    format!("{}:{}", options.host, options.port),
+                    Some(CleanerError::from(err)),
+                    progress,
+                    discovery_start.elapsed(),
+                ));
+                break;
+            }
+        };
+        let mut moved = false;
+        for (node, client) in nodes {
+            if done.contains(&node) {
+                continue;
+            }
+            // Cursors are only meaningful on the node that issued them.
+            let checkpoint_id = format!("{}@{}", checkpoint_id(conf, run.dry_run), node);
+            let node_start = Instant::now();
+            let mut retries = 0;
+            let backend = run
+                .retry
+                .run(&format!("Connection to {}", node), &mut retries, || {
+                    RedisBackend::connect(node.clone(), &client, true)
+                })
+                .await;
+            let checkpoint = open_checkpoint(options, store, checkpoint_id).await;
+            let connect_time = node_start.elapsed();
+            let (error, mut progress) = match (backend, checkpoint) {
+                (Ok(mut backend), Ok(mut checkpoint)) => {
+                    expire_keys(&mut backend, run, conf, &mut checkpoint).await
+                }
+                (Err(err), _) => (Some(CleanerError::from(err)), ScanProgress::default()),
+                (_, Err(err)) => (Some(err), ScanProgress::default()),
+            };
+            progress.connect_time = connect_time;
+            progress.retries += retries;
+            if let Some(err) = &error {
+                // The slot layout changed under us (MOVED/ASK, failover), rediscover the
+                // primaries and process the nodes that have not completed yet.
+                if err.is_topology_error() && attempt < MAX_TOPOLOGY_REFRESHES {
+                    info!(
+                        "{} - Cluster topology changed at {}: {}",
+                        conf.name, node, err
+                    );
+                    moved = true;
+                    continue;
+                }
+            }
+            done.insert(node.clone());
+            results.push(node_result(node, error, progress, node_start.elapsed()));
+        }
+        if !moved {
+            break;
+        }
+    }
+    results
+}
+
+fn node_result(
+    node: String,
+    error: Option<CleanerError>,
+    progress: ScanProgress,
+    total: Duration,
+) -> NodeResult {
+    NodeResult {
+        node,
+        processed_keys: progress.counts.total(),
+        ttl_added: progress.counts.ttl_added,
+        ttl_shortened: progress.counts.ttl_shortened,
+        deleted: progress.counts.deleted,
+        persisted: progress.counts.persisted,
+        keys_by_type: progress.counts.by_type,
+        excluded_keys: progress.excluded,
+        iterations: progress.iterations,
+        error_msg: error
+            .as_ref()
+            .map(|e| redact(&e.to_string()))
+            .unwrap_or_default(),
+        error_kind: error.as_ref().map(|e| e.category()),
+        resumed: progress.resumed,
+        completed: progress.completed,
+        incomplete_reason: progress.stopped,
+        timings: Timings {
+            connect_ms: millis(progress.connect_time),
+            scan_ms: millis(progress.scan_time),
+            expire_ms: millis(progress.expire_time),
+            throttle_ms: millis(progress.throttle_time),
+            total_ms: millis(total),
+        },
+        retries: progress.retries,
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// The settings shared by every rule of a [`Cleaner`].
+#[derive(Debug, Clone)]
+struct RunSettings {
+    store: CheckpointStore,
+    lock: RunLock,
+    retry: RetryPolicy,
+    /// The global rate limits, shared by every rule.
+    limiter: RateLimiter,
+    dry_run: bool,
+    /// Limits how many rules run at the same time, `None` if they all run at once.
+    concurrency: Option<Arc<Semaphore>>,
+}
+
+async fn cleanup(
+    options: RedisOptions,
+    settings: RunSettings,
+    conf: CleanupConfig,
+) -> ProcessingResult {
+    let RunSettings {
+        store,
+        lock,
+        retry,
+        limiter,
+        dry_run,
+        concurrency,
+    } = settings;
+    // The semaphore is never closed, a failed acquire only means there is no limit.
+    let _permit = match &concurrency {
+        Some(semaphore) => semaphore.acquire().await.ok(),
+        None => None,
+    };
+    let options = match resolve_primary(&options).await {
+        Ok(options) => RedisOptions {
+            db: conf.db.unwrap_or(options.db),
+            ..options
+        },
+        Err(err) => {
+            let mut result = failed_result(conf, format!("cannot resolve the primary: {}", err));
+            result.error_kind = Some(err.category());
+            return result;
+        }
+    };
+    if options.cluster && options.db != 0 {
+        let mut result = failed_result(conf, "Redis Cluster only supports database 0".to_string());
+        result.error_kind = Some(ErrorCategory::Config);
+        return result;
+    }
+    let lock = match lock.acquire(&options, &rule_id(&conf)).await {
+        Ok(Some(guard)) => guard,
+        Ok(None) => {
+            info!("{} - Skipped, another instance holds the lock", conf.name);
+            return skipped_result(conf);
+        }
+        Err(err) => {
+            let mut result = failed_result(conf, format!("cannot take the lock: {}", err));
+            result.error_kind = Some(err.category());
+            return result;
+        }
+    };
+    let limiter = limiter.with(RateLimit::for_config(&conf));
+    let run = RuleRun {
+        retry: &retry,
+        limiter: &limiter,
+        dry_run,
+        lock: &lock,
+    };
+    let start = Instant::now();
+    let nodes = if options.cluster {
+        expire_keys_cluster(&options, &store, &run, &conf).await
+    } else {
+        expire_keys_standalone(options.clone(), &store, &run, &conf).await
+    };
+    let mut result = processing_result(conf, nodes, start.elapsed(), options.cluster);
+    if lock.is_lost() {
+        result = lock_lost_result(result);
+    }
+    lock.release().await;
+    result
+}
+
+async fn expire_keys_standalone(
+    mut options: RedisOptions,
+    store: &CheckpointStore,
+    run: &RuleRun<'_>,
+    conf: &CleanupConfig,
+) -> Vec<NodeResult> {
+    const MAX_FAILOVER_RETRIES: usize = 3;
+    let mut attempt = 0;
+    let mut retries = 0;
+    loop {
+        let start = Instant::now();
+        let address = format!("{}:{}", options.host, options.port);
+        let backend = match create_redis_client(
+            &options.protocol,
+            &options.host,
+            &options.port,
+            &options.username,
+            &options.password,
+            options.db,
+            &options.tls,
+        ) {
+            Ok(client) => {
+                run.retry
+                    .run(&format!("Connection to {}", address), &mut retries, || {
+                        RedisBackend::connect(address.clone(), &client, false)
+                    })
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        let checkpoint = open_checkpoint(&options, store, checkpoint_id(conf, run.dry_run)).await;
+        let connect_time = start.elapsed();
+        let (error, mut progress) = match (backend, checkpoint) {
+            (Ok(mut backend), Ok(mut checkpoint)) => {
+                expire_keys(&mut backend, run, conf, &mut checkpoint).await
+            }
+            (Err(err), _) => (Some(CleanerError::from(err)), ScanProgress::default()),
+            (_, Err(err)) => (Some(err), ScanProgress::default()),
+        };
+        progress.connect_time = connect_time;
+        // Retries before a failover are kept, the result covers every attempt.
+        progress.retries += std::mem::take(&mut retries);
+        if let Some(err) = &error {
+            // The primary failed over (READONLY from a demoted node, or gone), ask the
+            // sentinels for the new one and continue from the checkpoint.
+            if options.sentinel.is_some()
+                && err.is_failover_error()
+                && attempt < MAX_FAILOVER_RETRIES
+            {
+                info!(
+                    "{} - Primary {} is not available: {}",
+                    conf.name, address, err
+                );
+                if let Ok(resolved) = resolve_primary(&options).await {
+                    attempt += 1;
+                    retries = progress.retries;
+                    options = resolved;
+                    continue;
+                }
+            }
+        }
+        return vec![node_result(address, error, progress, start.elapsed())];
+    }
+}
+
+/// Runs a single rule against `backend`, e.g. a [`MemoryBackend`](crate::memory::MemoryBackend).
+///
+/// The `redis` checkpoint store needs its own connection, so only the `file` store (or none)
+/// can be used here.
+pub async fn cleanup_with_backend<B: Backend>(
+    backend: &mut B,
+    store: &CheckpointStore,
+    retry: &RetryPolicy,
+    conf: &CleanupConfig,
+    dry_run: bool,
+) -> ProcessingResult {
+    let start = Instant::now();
+    let mut checkpoint = Checkpoint::new(store, checkpoint_id(conf, dry_run), None);
+    let limiter = RateLimiter::new(RateLimit::for_config(conf));
+    let run = RuleRun {
+        retry,
+        limiter: &limiter,
+        dry_run,
+        lock: &LockGuard::default(),
+    };
+    let (error, progress) = expire_keys(backend, &run, conf, &mut checkpoint).await;
+    let nodes = vec![node_result(
+        backend.address(),
+        error,
+        progress,
+        start.elapsed(),
+    )];
+    processing_result(conf.clone(), nodes, start.elapsed(), false)
+}
+
+fn processing_result(
+    conf: CleanupConfig,
+    nodes: Vec<NodeResult>,
+    duration: Duration,
+    cluster: bool,
+) -> ProcessingResult {
+    let error_msg = nodes
+        .iter()
+        .filter(|node| !node.error_msg.is_empty())
+        .map(|node| match cluster {
+            true => format!("{}: {}", node.node, node.error_msg),
+            false => node.error_msg.clone(),
+        })
+        .collect::<Vec<String>>()
+        .join("; ");
+    let completed = nodes.iter().all(|node| node.completed);
+    let status = if !error_msg.is_empty() {
+        ProcessingStatus::Failed
+    } else if !completed {
+        ProcessingStatus::Incomplete
+    } else {
+        ProcessingStatus::Success
+    };
+    ProcessingResult {
+        config: conf,
+        processed_keys: nodes.iter().map(|node| node.processed_keys).sum(),
+        ttl_added: nodes.iter().map(|node| node.ttl_added).sum(),
+        ttl_shortened: nodes.iter().map(|node| node.ttl_shortened).sum(),
+        deleted: nodes.iter().map(|node| node.deleted).sum(),
+        persisted: nodes.iter().map(|node| node.persisted).sum(),
+        keys_by_type: nodes.iter().fold(BTreeMap::new(), |mut by_type, node| {
+            for (key_type, keys) in node.keys_by_type.iter() {
+                *by_type.entry(*key_type).or_default() += keys;
+            }
+            by_type
+        }),
+        excluded_keys: nodes.iter().map(|node| node.excluded_keys).sum(),
+        iterations: nodes.iter().map(|node| node.iterations).sum(),
+        error_msg,
+        error_kind: nodes.iter().find_map(|node| node.error_kind),
+        execution_time: format!("{:?}", duration),
+        timings: Timings {
+            connect_ms: nodes.iter().map(|node| node.timings.connect_ms).sum(),
+            scan_ms: nodes.iter().map(|node| node.timings.scan_ms).sum(),
+            expire_ms: nodes.iter().map(|node| node.timings.expire_ms).sum(),
+            throttle_ms: nodes.iter().map(|node| node.timings.throttle_ms).sum(),
+            total_ms: millis(duration),
+        },
+        retries: nodes.iter().map(|node| node.retries).sum(),
+        status,
+        resumed: nodes.iter().any(|node| node.resumed),
+        completed,
+        incomplete_reason: nodes.iter().find_map(|node| node.incomplete_reason),
+        nodes,
+    }
+}
+
+/// Identifies the rule in checkpoints and locks, rules of different targets may share a name.
+fn rule_id(conf: &CleanupConfig) -> String {
+    match &conf.target {
+        Some(target) => format!("{}/{}", target, conf.name),
+        None => conf.name.clone(),
+    }
+}
+
+/// Dry runs keep their own checkpoint: a real run must never skip the keys a dry run walked
+/// past, nor a dry run count only the rest of an interrupted real run.
+fn checkpoint_id(conf: &CleanupConfig, dry_run: bool) -> String {
+    match dry_run {
+        true => format!("{}#dry-run", rule_id(conf)),
+        false => rule_id(conf),
+    }
+}
+
+fn skipped_result(conf: CleanupConfig) -> ProcessingResult {
+    ProcessingResult {
+        status: ProcessingStatus::Skipped,
+        completed: true,
+        error_kind: None,
+        ..failed_result(conf, String::new())
+    }
+}
+
+/// Another instance took the lock over while the rule ran, the nodes stopped at their next
+/// batch: the keys changed so far are kept in the result.
+fn lock_lost_result(result: ProcessingResult) -> ProcessingResult {
+    warn!("{} - Stopped, the lock was lost", result.config.name);
+    let error_msg = match result.error_msg.is_empty() {
+        true => "lock lost".to_string(),
+        false => format!("lock lost; {}", result.error_msg),
+    };
+    ProcessingResult {
+        status: ProcessingStatus::Failed,
+        error_msg,
+        ..result
+    }
+}
+
+fn failed_result(conf: CleanupConfig, error_msg: String) -> ProcessingResult {
+    ProcessingResult {
+        config: conf,
+        processed_keys: 0,
+        ttl_added: 0,
+        ttl_shortened: 0,
+        deleted: 0,
+        persisted: 0,
+        keys_by_type: BTreeMap::new(),
+        excluded_keys: 0,
+        iterations: 0,
+        error_msg: redact(&error_msg),
+        error_kind: None,
+        execution_time: format!("{:?}", Duration::ZERO),
+        timings: Timings::default(),
+        retries: 0,
+        status: ProcessingStatus::Failed,
+        resumed: false,
+        completed: false,
+        incomplete_reason: None,
+        nodes: Vec::new(),
+    }
+}
+
+/// Runs a set of cleanup rules against one Redis deployment, or several named targets.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), redis_cleaner::CleanerError> {
+/// use redis_cleaner::{load_configs, Cleaner, RedisOptions};
+///
+/// let cleaner = Cleaner::new(RedisOptions::from_env()?, load_configs("config.yaml")?).dry_run(true);
+/// for result in cleaner.run().await? {
+///     println!("{}: {} keys", result.config.name, result.processed_keys);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cleaner {
+    redis: Option<RedisOptions>,
+    targets: BTreeMap<String, RedisOptions>,
+    configs: Vec<CleanupConfig>,
+    settings: RunSettings,
+}
+
+impl Cleaner {
+    /// The passwords of the options are redacted from the logs, results and notifications.
+    pub fn new(redis: RedisOptions, configs: Vec<CleanupConfig>) -> Cleaner {
+        redis.register_secrets();
+        Cleaner {
+            redis: Some(redis),
+            ..Cleaner::with_targets(BTreeMap::new(), configs)
+        }
+    }
+
+    /// A cleaner without a default Redis, every rule must reference one of `targets`.
+    pub fn with_targets(
+        targets: BTreeMap<String, RedisOptions>,
+        configs: Vec<CleanupConfig>,
+    ) -> Cleaner {
+        targets.values().for_each(RedisOptions::register_secrets);
+        Cleaner {
+            redis: None,
+            targets,
+            configs,
+            settings: RunSettings {
+                store: CheckpointStore::Disabled,
+                lock: RunLock::Disabled,
+                retry: RetryPolicy::default(),
+                limiter: RateLimiter::default(),
+                dry_run: false,
+                concurrency: None,
+            },
+        }
+    }
+
+    /// Adds a named target, referenced by the `target` of the rules.
+    pub fn target(mut self, name: &str, options: RedisOptions) -> Cleaner {
+        options.register_secrets();
+        self.targets.insert(name.to_string(), options);
+        self
+    }
+
+    pub fn checkpoint_store(mut self, store: CheckpointStore) -> Cleaner {
+        self.settings.store = store;
+        self
+    }
+
+    /// The lock taken on every rule before it runs, see [`RunLock`].
+    pub fn run_lock(mut self, lock: RunLock) -> Cleaner {
+        self.settings.lock = lock;
+        self
+    }
+
+    /// How transient Redis errors are retried, see [`RetryPolicy`].
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Cleaner {
+        self.settings.retry = retry;
+        self
+    }
+
+    /// Rate limits shared by all rules, on top of the `maxKeysPerSecond` and
+    /// `maxScansPerSecond` of every rule.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Cleaner {
+        self.settings.limiter = RateLimiter::new(limit);
+        self
+    }
+
+    /// Limits how many rules run at the same time, the others wait for a free slot; `0`
+    /// (the default) runs every rule at once.
+    pub fn max_concurrent_rules(mut self, max: usize) -> Cleaner {
+        self.settings.concurrency = match max {
+            0 => None,
+            max => Some(Arc::new(Semaphore::new(max))),
+        };
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Cleaner {
+        self.settings.dry_run = dry_run;
+        self
+    }
+
+    pub fn configs(&self) -> &[CleanupConfig] {
+        &self.configs
+    }
+
+    /// The Redis of a rule: its `target`, or the default Redis.
+    fn rule_options(&self, conf: &CleanupConfig) -> Result<&RedisOptions, CleanerError> {
+        match &conf.target {
+            Some(target) => self.targets.get(target).ok_or_else(|| {
+                CleanerError::Config(format!(
+                    "rule '{}' references an unknown target: {}",
+                    conf.name, target
+                ))
+            }),
+            None => self
+                .redis
+                .as_ref()
+                .ok_or_else(|| CleanerError::Config(format!("rule '{}' has no target", conf.name))),
+        }
+    }
+
+    /// Checks that every rule has a Redis and that the used ones are reachable. The run can
+    /// not start if none of them is, the rules of a single unreachable target fail on their own.
+    async fn check_targets(&self, configs: &[CleanupConfig]) -> Result<(), CleanerError> {
+        let mut checked = BTreeSet::new();
+        let mut unreachable = Vec::new();
+        for config in configs.iter() {
+            let options = self.rule_options(config)?;
+            if checked.insert(config.target.clone()) {
+                let target = config.target.as_deref().unwrap_or("default");
+                info!("Target {} - Cluster mode: {}", target, options.cluster);
+                let mut retries = 0;
+                let checked = self
+                    .settings
+                    .retry
+                    .run(&format!("Target {}", target), &mut retries, || {
+                        check_connection(options)
+                    })
+                    .await;
+                if let Err(err) = checked {
+                    error!("Target {} - {}", target, err);
+                    unreachable.push(err);
+                }
+            }
+        }
+        match unreachable.len() == checked.len() {
+            true => unreachable.into_iter().next().map_or(Ok(()), Err),
+            false => Ok(()),
+        }
+    }
+
+    /// Runs every rule concurrently and returns one result per rule, in configuration order.
+    ///
+    /// An error is only returned if the run could not start; failures of single rules are
+    /// reported in their `ProcessingResult`.
+    pub async fn run(&self) -> Result<Vec<ProcessingResult>, CleanerError> {
+        info!("Dry run: {}", self.settings.dry_run);
+        self.check_targets(&self.configs).await?;
+        let mut handles = Vec::new();
+        for config in self.configs.iter() {
+            let job = tokio::spawn(cleanup(
+                self.rule_options(config)?.clone(),
+                self.settings.clone(),
+                config.clone(),
+            ));
+            handles.push(job);
+        }
+        let mut results = Vec::new();
+        for (config, job) in self.configs.iter().zip(handles) {
+            match job.await {
+                Ok(result) => results.push(result),
+                Err(err) => results.push(failed_result(config.clone(), err.to_string())),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Runs every rule with a `schedule` on its cron schedule and sends the result of each
+    /// run to `results`, rules without a schedule are ignored.
+    ///
+    /// A rule never overlaps with itself: scheduled times that elapse while its previous run
+    /// is still going are skipped. Invalid schedules are reported before anything runs; the
+    /// returned future only completes if no rule has an upcoming run or `results` is closed.
+    pub async fn run_daemon(
+        &self,
+        results: UnboundedSender<ProcessingResult>,
+    ) -> Result<(), CleanerError> {
+        let mut scheduled = Vec::new();
+        for config in self.configs.iter() {
+            match RuleSchedule::for_config(config)? {
+                Some(schedule) => scheduled.push((config.clone(), schedule)),
+                None => warn!("{} - No schedule, ignored in daemon mode", config.name),
+            }
+        }
+        if scheduled.is_empty() {
+            return Err(CleanerError::Config(
+                "no rule has a schedule, nothing to run in daemon mode".to_string(),
+            ));
+        }
+        info!("Dry run: {}", self.settings.dry_run);
+        let configs: Vec<CleanupConfig> = scheduled.iter().map(|(conf, _)| conf.clone()).collect();
+        self.check_targets(&configs).await?;
+        let mut handles = Vec::new();
+        for (config, schedule) in scheduled {
+            handles.push(tokio::spawn(run_scheduled(
+                self.rule_options(&config)?.clone(),
+                self.settings.clone(),
+                config,
+                schedule,
+                results.clone(),
+            )));
+        }
+        for handle in handles {
+            // a panicking rule must not stop the other schedules
+            let _ = handle.await;
+        }
+        Ok(())
+    }
+}
+
+async fn run_scheduled(
+    options: RedisOptions,
+    settings: RunSettings,
+    conf: CleanupConfig,
+    schedule: RuleSchedule,
+    results: UnboundedSender<ProcessingResult>,
+) {
+    loop {
+        let now = Utc::now();
+        let next = match schedule.next_after(now) {
+            Some(next) => next,
+            None => {
+                warn!("{} - No upcoming scheduled run", conf.name);
+                return;
+            }
+        };
+        info!("{} - Next run at {}", conf.name, next);
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+        let job = tokio::spawn(cleanup(options.clone(), settings.clone(), conf.clone()));
+        let result = match job.await {
+            Ok(result) => result,
+            Err(err) => failed_result(conf.clone(), err.to_string()),
+        };
+        let skipped = schedule.count_between(next, Utc::now());
+        if skipped > 0 {
+            warn!(
+                "{} - Skipped {} scheduled runs, the previous run was still in progress",
+                conf.name, skipped
+            );
+        }
+        if results.send(result).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cleanup, cleanup_with_backend, expire_keys, lock_lost_result, node_result,
+        processing_result, Cleaner, RuleRun,
+    };
+    use crate::checkpoint::{Checkpoint, CheckpointStore};
+    use crate::config::{
+        CleanupAction, CleanupConfig, CleanupEngine, KeyType, RedisOptions, TlsOptions,
+    };
+    use crate::error::ErrorCategory;
+    use crate::lock::LockGuard;
+    use crate::memory::MemoryBackend;
+    use crate::rate::{RateLimit, RateLimiter};
+    use crate::result::{IncompleteReason, ProcessingResult, ProcessingStatus};
+    use crate::retry::RetryPolicy;
+    use crate::throttle::ThrottleConfig;
+    use redis::ErrorKind;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn rule(pattern: &str, engine: CleanupEngine) -> CleanupConfig {
+        CleanupConfig {
+            engine,
+            ..CleanupConfig::new(&format!("rule {}", pattern), pattern, 60, 10)
+        }
+    }
+
+    fn no_retry() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    async fn run(
+        backend: &mut MemoryBackend,
+        conf: &CleanupConfig,
+        dry_run: bool,
+    ) -> ProcessingResult {
+        cleanup_with_backend(
+            backend,
+            &CheckpointStore::Disabled,
+            &no_retry(),
+            conf,
+            dry_run,
+        )
+        .await
+    }
+
+    fn unreachable() -> RedisOptions {
+        RedisOptions {
+            protocol: "redis".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: "1".to_string(),
+            username: String::new(),
+            password: String::new(),
+            db: 0,
+            cluster: false,
+            sentinel: None,
+            tls: TlsOptions::default(),
+        }
+    }
+
+    fn keyspace() -> MemoryBackend {
+        let mut backend = MemoryBackend::new();
+        for i in 0..30 {
+            backend.insert(&format!("cache:{:02}", i));
+        }
+        for i in 0..5 {
+            backend.insert_with_ttl(&format!("cache:ttl:{}", i), 3600);
+        }
+        for i in 0..10 {
+            backend.insert(&format!("session:{}", i));
+        }
+        backend
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_sets_missing_ttls() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            let mut backend = keyspace();
+            let conf = rule("cache:*", engine);
+            let result = run(&mut backend, &conf, false).await;
+            assert_eq!(result.status, ProcessingStatus::Success);
+            assert_eq!(result.processed_keys, 30);
+            assert!(result.completed);
+            assert!(result.error_msg.is_empty());
+            assert_eq!(backend.ttl_of("cache:00"), 60);
+            assert_eq!(backend.ttl_of("cache:ttl:0"), 3600);
+            assert_eq!(backend.ttl_of("session:0"), -1);
+            backend.advance(61);
+            assert_eq!(backend.len(), 15);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_only_counts() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            let mut backend = keyspace();
+            let conf = rule("session:*", engine);
+            let result = run(&mut backend, &conf, true).await;
+            assert_eq!(result.status, ProcessingStatus::Success);
+            assert_eq!(result.processed_keys, 10);
+            assert_eq!(backend.ttl_of("session:0"), -1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_second_run_finds_nothing() {
+        let mut backend = keyspace();
+        let conf = rule("cache:*", CleanupEngine::Scan);
+        run(&mut backend, &conf, false).await;
+        let result = run(&mut backend, &conf, false).await;
+        assert_eq!(result.processed_keys, 0);
+        assert_eq!(result.iterations, 5);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_scan_is_incomplete_and_resumes() {
+        let path =
+            std::env::temp_dir().join(format!("redis-cleaner-test-{}.json", std::process::id()));
+        let store = CheckpointStore::file(path.to_str().unwrap());
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            let mut backend = keyspace();
+            let mut conf = rule("cache:*", engine);
+            conf.max_iterations = 2;
+            let first = cleanup_with_backend(&mut backend, &store, &no_retry(), &conf, false).await;
+            assert_eq!(first.status, ProcessingStatus::Incomplete);
+            assert!(!first.completed);
+            assert!(!first.resumed);
+            assert_eq!(first.iterations, 2);
+            assert_eq!(
+                first.incomplete_reason,
+                Some(IncompleteReason::MaxIterations)
+            );
+            conf.max_iterations = 100;
+            let second =
+                cleanup_with_backend(&mut backend, &store, &no_retry(), &conf, false).await;
+            assert_eq!(second.status, ProcessingStatus::Success);
+            assert!(second.resumed);
+            assert!(second.completed);
+            assert_eq!(second.incomplete_reason, None);
+            assert_eq!(first.processed_keys + second.processed_keys, 30);
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_dry_runs_keep_their_own_checkpoint() {
+        let path = std::env::temp_dir().join(format!(
+            "redis-cleaner-dry-run-test-{}.json",
+            std::process::id()
+        ));
+        let store = CheckpointStore::file(path.to_str().unwrap());
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            let mut backend = keyspace();
+            let mut conf = rule("cache:*", engine);
+            conf.max_iterations = 2;
+            let dry = cleanup_with_backend(&mut backend, &store, &no_retry(), &conf, true).await;
+            assert_eq!(dry.status, ProcessingStatus::Incomplete);
+            conf.max_iterations = 100;
+            let real = cleanup_with_backend(&mut backend, &store, &no_retry(), &conf, false).await;
+            assert_eq!(real.status, ProcessingStatus::Success);
+            assert!(!real.resumed);
+            assert_eq!(real.processed_keys, 30);
+            let dry = cleanup_with_backend(&mut backend, &store, &no_retry(), &conf, true).await;
+            assert!(dry.resumed);
+            assert!(dry.completed);
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_timings_cover_the_whole_run() {
+        let mut backend = keyspace();
+        let conf = CleanupConfig {
+            batch_pause_ms: 5,
+            ..rule("cache:*", CleanupEngine::Scan)
+        };
+        let result = run(&mut backend, &conf, false).await;
+        let timings = result.timings;
+        assert_eq!(result.iterations, 5);
+        // 4 pauses between the 5 batches
+        assert!(timings.total_ms >= 20.0);
+        assert!(timings.total_ms >= timings.connect_ms + timings.scan_ms + timings.expire_ms);
+        assert!(timings.scan_ms > 0.0);
+        assert!(timings.expire_ms > 0.0);
+        assert_eq!(result.nodes[0].timings.scan_ms, timings.scan_ms);
+        assert_ne!(
+            result.execution_time,
+            format!("{:?}", std::time::Duration::ZERO)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_daemon_needs_a_scheduled_rule() {
+        let options = unreachable();
+        let (tx, _rx) = unbounded_channel();
+        let cleaner = Cleaner::new(options, vec![rule("cache:*", CleanupEngine::Scan)]);
+        let err = cleaner.run_daemon(tx).await.unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        assert!(err.to_string().contains("no rule has a schedule"));
+    }
+
+    #[tokio::test]
+    async fn test_rules_need_a_known_target() {
+        let staging = CleanupConfig {
+            target: Some("staging".to_string()),
+            ..rule("cache:*", CleanupEngine::Scan)
+        };
+        let cleaner = Cleaner::with_targets(BTreeMap::new(), vec![staging]);
+        let err = cleaner.run().await.unwrap_err();
+        assert!(err.to_string().contains("unknown target: staging"));
+        let cleaner = Cleaner::with_targets(BTreeMap::new(), vec![rule("*", CleanupEngine::Lua)]);
+        let err = cleaner.run().await.unwrap_err();
+        assert!(err.to_string().contains("has no target"));
+    }
+
+    #[tokio::test]
+    async fn test_backend_errors_fail_the_rule() {
+        let mut backend = keyspace();
+        backend.fail_with(ErrorKind::BusyLoadingError, "Redis is loading the dataset");
+        let conf = rule("cache:*", CleanupEngine::Scan);
+        let result = run(&mut backend, &conf, false).await;
+        assert_eq!(result.status, ProcessingStatus::Failed);
+        assert_eq!(result.error_kind, Some(ErrorCategory::Script));
+        assert!(result.error_msg.contains("loading"));
+        assert_eq!(result.nodes.len(), 1);
+        assert_eq!(result.nodes[0].node, "memory");
+    }
+
+    #[tokio::test]
+    async fn test_error_messages_are_redacted() {
+        let options = RedisOptions {
+            password: "hunter2-cleaner-test".to_string(),
+            ..unreachable()
+        };
+        let _ = Cleaner::new(options, Vec::new());
+        let mut backend = keyspace();
+        backend.fail_with(
+            ErrorKind::AuthenticationFailed,
+            "WRONGPASS hunter2-cleaner-test",
+        );
+        let conf = rule("cache:*", CleanupEngine::Scan);
+        let result = run(&mut backend, &conf, false).await;
+        assert_eq!(result.status, ProcessingStatus::Failed);
+        assert!(result.error_msg.contains("WRONGPASS ***"));
+        assert!(!result.error_msg.contains("hunter2"));
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried() {
+        let mut backend = keyspace();
+        backend.fail_times(
+            ErrorKind::BusyLoadingError,
+            "Redis is loading the dataset",
+            2,
+        );
+        let conf = rule("cache:*", CleanupEngine::Scan);
+        let retry = RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(2));
+        let result = cleanup_with_backend(
+            &mut backend,
+            &CheckpointStore::Disabled,
+            &retry,
+            &conf,
+            false,
+        )
+        .await;
+        assert_eq!(result.status, ProcessingStatus::Success);
+        assert_eq!(result.processed_keys, 30);
+        assert_eq!(result.retries, 2);
+        assert_eq!(result.nodes[0].retries, 2);
+        backend.fail_times(
+            ErrorKind::BusyLoadingError,
+            "Redis is loading the dataset",
+            5,
+        );
+        let result = cleanup_with_backend(
+            &mut backend,
+            &CheckpointStore::Disabled,
+            &retry,
+            &conf,
+            false,
+        )
+        .await;
+        assert_eq!(result.status, ProcessingStatus::Failed);
+        assert_eq!(result.retries, 3);
+    }
+
+    #[tokio::test]
+    async fn test_rules_wait_for_a_free_slot() {
+        let options = unreachable();
+        let cleaner = Cleaner::new(options.clone(), Vec::new()).max_concurrent_rules(1);
+        let semaphore = cleaner.settings.concurrency.clone().unwrap();
+        let permit = semaphore.acquire().await.unwrap();
+        let job = tokio::spawn(cleanup(
+            options,
+            cleaner.settings.clone(),
+            rule("cache:*", CleanupEngine::Scan),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!job.is_finished());
+        drop(permit);
+        let result = job.await.unwrap();
+        assert_eq!(result.status, ProcessingStatus::Failed);
+        assert_eq!(result.error_kind, Some(ErrorCategory::Connection));
+        assert!(Cleaner::with_targets(BTreeMap::new(), Vec::new())
+            .max_concurrent_rules(0)
+            .settings
+            .concurrency
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_spaces_out_batches() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            let mut backend = keyspace();
+            let mut conf = rule("cache:*", engine);
+            conf.max_keys_per_second = Some(200);
+            let start = std::time::Instant::now();
+            let result = run(&mut backend, &conf, false).await;
+            // 5 batches of up to 10 keys, at most 200 keys per second
+            assert!(
+                start.elapsed() >= Duration::from_millis(150),
+                "{:?}",
+                engine
+            );
+            assert_eq!(result.status, ProcessingStatus::Success);
+            assert_eq!(result.processed_keys, 30);
+            assert_eq!(result.iterations, 5);
+            assert_eq!(backend.ttl_of("cache:29"), 60);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_throttle_pauses_while_the_server_is_loaded() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            let mut backend = keyspace();
+            backend.queue_info("instantaneous_ops_per_sec:90000\r\n");
+            backend.queue_info("instantaneous_ops_per_sec:60000\r\n");
+            backend.queue_info("instantaneous_ops_per_sec:1000\r\n");
+            let mut conf = rule("cache:*", engine);
+            conf.throttle = Some(ThrottleConfig {
+                max_ops_per_sec: Some(50000),
+                min_pause_ms: 10,
+                ..ThrottleConfig::default()
+            });
+            let result = run(&mut backend, &conf, false).await;
+            assert_eq!(result.status, ProcessingStatus::Success);
+            assert_eq!(result.processed_keys, 30);
+            assert_eq!(result.timings.throttle_ms, 30.0, "{:?}", engine);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_throttle_gives_up_as_incomplete() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            let mut backend = keyspace();
+            backend.queue_info("instantaneous_ops_per_sec:90000\r\n");
+            let mut conf = rule("cache:*", engine);
+            conf.throttle = Some(ThrottleConfig {
+                max_ops_per_sec: Some(50000),
+                min_pause_ms: 100,
+                max_wait_ms: 300,
+                ..ThrottleConfig::default()
+            });
+            let result = run(&mut backend, &conf, false).await;
+            assert_eq!(result.status, ProcessingStatus::Incomplete, "{:?}", engine);
+            assert_eq!(result.processed_keys, 0);
+            assert!(result.timings.throttle_ms >= 300.0);
+            assert_eq!(
+                result.incomplete_reason,
+                Some(IncompleteReason::ThrottleWaitExceeded)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_wait_for_lagging_replicas() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            let mut backend = keyspace();
+            let replication = |offset: u64| {
+                format!(
+                    "slave0:ip=10.0.0.2,port=6379,state=online,offset={},lag=0\r\nmaster_repl_offset:10000\r\n",
+                    offset
+                )
+            };
+            backend.queue_info(&replication(5000));
+            backend.queue_info(&replication(8000));
+            backend.queue_info(&replication(9500));
+            let mut conf = rule("cache:*", engine);
+            conf.max_replica_lag_bytes = Some(1000);
+            let result = run(&mut backend, &conf, false).await;
+            assert_eq!(result.status, ProcessingStatus::Success);
+            assert_eq!(result.processed_keys, 30);
+            assert_eq!(result.timings.throttle_ms, 200.0, "{:?}", engine);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replica_wait_gives_up_as_incomplete() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            let mut backend = keyspace();
+            backend.queue_info(
+                "slave0:ip=10.0.0.2,port=6379,state=online,offset=5000,lag=0\r\nmaster_repl_offset:10000\r\n",
+            );
+            let mut conf = rule("cache:*", engine);
+            conf.max_replica_lag_bytes = Some(1000);
+            conf.max_replica_wait_ms = 300;
+            let result = run(&mut backend, &conf, false).await;
+            assert_eq!(result.status, ProcessingStatus::Incomplete, "{:?}", engine);
+            assert_eq!(result.processed_keys, 0);
+            assert!(result.timings.throttle_ms >= 300.0);
+            assert_eq!(
+                result.incomplete_reason,
+                Some(IncompleteReason::ReplicaWaitExceeded)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lost_lock_stops_the_rule() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            let mut backend = keyspace();
+            let conf = rule("cache:*", engine);
+            let limiter = RateLimiter::new(RateLimit::for_config(&conf));
+            let lock = LockGuard::lost();
+            let run = RuleRun {
+                retry: &no_retry(),
+                limiter: &limiter,
+                dry_run: false,
+                lock: &lock,
+            };
+            let mut checkpoint =
+                Checkpoint::new(&CheckpointStore::Disabled, "rule".to_string(), None);
+            let (error, progress) = expire_keys(&mut backend, &run, &conf, &mut checkpoint).await;
+            assert!(error.is_none());
+            assert!(!progress.completed);
+            assert_eq!(progress.iterations, 0);
+            let nodes = vec![node_result(
+                "memory".to_string(),
+                error,
+                progress,
+                Duration::ZERO,
+            )];
+            let result = lock_lost_result(processing_result(conf, nodes, Duration::ZERO, false));
+            assert_eq!(result.status, ProcessingStatus::Failed, "{:?}", engine);
+            assert_eq!(result.error_msg, "lock lost");
+            assert_eq!(result.incomplete_reason, Some(IncompleteReason::LockLost));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_ttl_shortens_long_ttls() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            for dry_run in [true, false] {
+                let mut backend = keyspace();
+                backend.insert_with_ttl("cache:short", 30);
+                let mut conf = rule("cache:*", engine);
+                conf.max_ttl_seconds = Some(600);
+                let result = run(&mut backend, &conf, dry_run).await;
+                assert_eq!(result.status, ProcessingStatus::Success);
+                assert_eq!((result.ttl_added, result.ttl_shortened), (30, 5));
+                assert_eq!(result.processed_keys, 35);
+                let expected = match dry_run {
+                    true => (3600, -1),
+                    false => (600, 60),
+                };
+                assert_eq!(
+                    (backend.ttl_of("cache:ttl:0"), backend.ttl_of("cache:00")),
+                    expected
+                );
+                assert_eq!(backend.ttl_of("cache:short"), 30);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_max_ttl_fails_the_rule() {
+        for max_ttl in [0, -5, 30] {
+            let mut backend = keyspace();
+            let mut conf = rule("cache:*", CleanupEngine::Lua);
+            conf.max_ttl_seconds = Some(max_ttl);
+            let result = run(&mut backend, &conf, false).await;
+            assert_eq!(result.status, ProcessingStatus::Failed, "{}", max_ttl);
+            assert_eq!(result.error_kind, Some(ErrorCategory::Config));
+            assert_eq!(result.processed_keys, 0);
+        }
+        let mut backend = keyspace();
+        let mut conf = rule("cache:*", CleanupEngine::Lua);
+        conf.max_ttl_seconds = Some(60);
+        assert_eq!(
+            run(&mut backend, &conf, false).await.status,
+            ProcessingStatus::Success
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_and_persist_actions() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            for dry_run in [true, false] {
+                let mut backend = keyspace();
+                let mut conf = rule("cache:*", engine);
+                conf.action = CleanupAction::Persist;
+                let result = run(&mut backend, &conf, dry_run).await;
+                assert_eq!((result.persisted, result.processed_keys), (5, 5));
+                assert_eq!(result.ttl_added, 0);
+                let ttl = backend.ttl_of("cache:ttl:0");
+                assert_eq!(ttl, if dry_run { 3600 } else { -1 });
+                conf.action = CleanupAction::Delete;
+                let result = run(&mut backend, &conf, dry_run).await;
+                assert_eq!(result.status, ProcessingStatus::Success);
+                assert_eq!((result.deleted, result.processed_keys), (35, 35));
+                assert_eq!(backend.len(), if dry_run { 45 } else { 10 });
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_usage_filters_select_idle_or_rare_keys() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            let mut backend = keyspace();
+            for i in 0..10 {
+                backend.set_usage(&format!("cache:{:02}", i), 7200, 0);
+            }
+            backend.set_usage("cache:10", 60, 0);
+            backend.queue_info("# Memory\r\nmaxmemory_policy:allkeys-lru\r\n");
+            let mut conf = rule("cache:*", engine);
+            conf.min_idle_seconds = Some(3600);
+            let result = run(&mut backend, &conf, false).await;
+            assert_eq!(result.status, ProcessingStatus::Success);
+            assert_eq!(result.ttl_added, 10);
+            assert_eq!(backend.ttl_of("cache:09"), 60);
+            assert_eq!(backend.ttl_of("cache:10"), -1);
+
+            // FREQ is only tracked under an LFU policy, the rule fails before any change.
+            conf.min_idle_seconds = None;
+            conf.max_frequency = Some(5);
+            let result = run(&mut backend, &conf, false).await;
+            assert_eq!(result.status, ProcessingStatus::Failed);
+            assert_eq!(result.error_kind, Some(ErrorCategory::Config));
+            assert!(
+                result.error_msg.contains("allkeys-lru"),
+                "{}",
+                result.error_msg
+            );
+            assert_eq!(backend.ttl_of("cache:10"), -1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_types_select_keys_by_type() {
+        for engine in [CleanupEngine::Lua, CleanupEngine::Scan] {
+            // SCAN ... TYPE on 7.2, a TYPE check per key on 5.0
+            for version in ["7.2.4", "5.0.14"] {
+                let mut backend = keyspace();
+                for i in 0..4 {
+                    backend.insert_typed(&format!("session:h:{}", i), "hash", None);
+                }
+                backend.insert_typed("session:z", "zset", None);
+                backend.queue_info(&format!("# Server\r\nredis_version:{}\r\n", version));
+                let mut conf = rule("session:*", engine);
+                conf.types = vec![KeyType::Hash];
+                let result = run(&mut backend, &conf, false).await;
+                assert_eq!(result.status, ProcessingStatus::Success);
+                assert_eq!(result.ttl_added, 4);
+                assert_eq!(result.keys_by_type, BTreeMap::from([(KeyType::Hash, 4)]));
+                assert_eq!(backend.ttl_of("session:h:0"), 60);
+                assert_eq!(backend.ttl_of("session:0"), -1);
+
+                conf.types = vec![KeyType::String, KeyType::Zset];
+                let result = run(&mut backend, &conf, false).await;
+                assert_eq!(result.ttl_added, 11);
+                assert_eq!(
+                    result.keys_by_type,
+                    BTreeMap::from([(KeyType::String, 10), (KeyType::Zset, 1)])
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_include_and_exclude_globs() {
+        let mut backend = keyspace();
+        for i in 0..3 {
+            backend.insert(&format!("cache:config:{}", i));
+        }
+        backend.insert("cache:tmp");
+        let mut conf = rule("cache:*", CleanupEngine::Scan);
+        conf.include = vec!["session:*".to_string()];
+        conf.exclude = vec!["cache:config:*".to_string()];
+        conf.regex = Some(r"[0-9]$".to_string());
+        let result = run(&mut backend, &conf, false).await;
+        assert_eq!(result.status, ProcessingStatus::Success);
+        assert_eq!(result.ttl_added, 40);
+        assert_eq!(result.excluded_keys, 4);
+        assert_eq!(backend.ttl_of("session:3"), 60);
+        assert_eq!(backend.ttl_of("cache:config:0"), -1);
+        assert_eq!(backend.ttl_of("cache:tmp"), -1);
+
+        conf.engine = CleanupEngine::Lua;
+        let result = run(&mut backend, &conf, false).await;
+        assert_eq!(result.error_kind, Some(ErrorCategory::Config));
+        assert!(
+            result.error_msg.contains("scan engine"),
+            "{}",
+            result.error_msg
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use redis::{ErrorKind, RedisError};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+/// The kind of failure, it also determines the exit code of the process.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorCategory {
+    Config,
+    Connection,
+    Script,
+    Checkpoint,
+    Template,
+    Notification,
+}
+
+impl ErrorCategory {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorCategory::Config => 3,
+            ErrorCategory::Connection => 4,
+            ErrorCategory::Script => 5,
+            ErrorCategory::Checkpoint => 6,
+            ErrorCategory::Template => 7,
+            ErrorCategory::Notification => 8,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CleanerError {
+    Config(String),
+    Connection(RedisError),
+    Script(RedisError),
+    Checkpoint(String),
+    Template(tera::Error),
+    Notification(String),
+}
+
+impl CleanerError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            CleanerError::Config(_) => ErrorCategory::Config,
+            CleanerError::Connection(_) => ErrorCategory::Connection,
+            CleanerError::Script(_) => ErrorCategory::Script,
+            CleanerError::Checkpoint(_) => ErrorCategory::Checkpoint,
+            CleanerError::Template(_) => ErrorCategory::Template,
+            CleanerError::Notification(_) => ErrorCategory::Notification,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.category().exit_code()
+    }
+
+    /// True if the node is no longer a reachable, writable primary, e.g. after a Sentinel failover.
+    pub fn is_failover_error(&self) -> bool {
+        match self {
+            CleanerError::Connection(err) => err.kind() != ErrorKind::AuthenticationFailed,
+            CleanerError::Script(err) => err.kind() == ErrorKind::ReadOnly,
+            _ => false,
+        }
+    }
+
+    /// True if the cluster slot layout changed while a node was processed (MOVED/ASK, failover).
+    pub fn is_topology_error(&self) -> bool {
+        match self {
+            CleanerError::Connection(err) | CleanerError::Script(err) => matches!(
+                err.kind(),
+                ErrorKind::Moved | ErrorKind::Ask | ErrorKind::ReadOnly | ErrorKind::ClusterDown
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for CleanerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CleanerError::Config(msg) => write!(f, "configuration error: {}", msg),
+            CleanerError::Connection(err) => write!(f, "connection error: {}", err),
+            CleanerError::Script(err) => write!(f, "script error: {}", err),
+            CleanerError::Checkpoint(msg) => write!(f, "checkpoint error: {}", msg),
+            CleanerError::Template(err) => {
+                // Tera keeps the useful details (e.g. the failing line) in the source chain.
+                write!(f, "template error: {}", err)?;
+                let mut source = err.source();
+                while let Some(cause) = source {
+                    write!(f, ": {}", cause)?;
+                    source = cause.source();
+                }
+                Ok(())
+            }
+            CleanerError::Notification(msg) => write!(f, "notification error: {}", msg),
+        }
+    }
+}
+
+impl Error for CleanerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CleanerError::Connection(err) | CleanerError::Script(err) => Some(err),
+            CleanerError::Template(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<RedisError> for CleanerError {
+    fn from(err: RedisError) -> Self {
+        if err.kind() == ErrorKind::InvalidClientConfig {
+            CleanerError::Config(err.to_string())
+        } else if err.kind() == ErrorKind::AuthenticationFailed
+            || err.is_io_error()
+            || err.is_connection_refusal()
+            || err.is_connection_dropped()
+            || err.is_timeout()
+        {
+            CleanerError::Connection(err)
+        } else {
+            CleanerError::Script(err)
+        }
+    }
+}
+
+impl From<tera::Error> for CleanerError {
+    fn from(err: tera::Error) -> Self {
+        CleanerError::Template(err)
+    }
+}
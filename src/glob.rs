@@ -0,0 +1,128 @@
+/// Matches `key` against a Redis glob `pattern` (`*`, `?`, `[a-z]`, `[^abc]` and `\` escapes),
+/// with the same semantics as the `MATCH` option of `SCAN`.
+pub fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // Position of the last `*` in the pattern and the key index it is currently matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p, k));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    k += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, key[k]) {
+                        if matched {
+                            p = next;
+                            k += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == key[k] {
+                        p += 2;
+                        k += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == key[k] {
+                        p += 1;
+                        k += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        // Mismatch: let the last `*` swallow one more byte of the key, if there is one.
+        match star {
+            Some((star_p, star_k)) => {
+                p = star_p + 1;
+                k = star_k + 1;
+                star = Some((star_p, star_k + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Matches `c` against the `[...]` class starting at `start`, returns the match and the
+/// pattern index after the class.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (low, high) = match pattern[i] <= pattern[i + 2] {
+                true => (pattern[i], pattern[i + 2]),
+                false => (pattern[i + 2], pattern[i]),
+            };
+            matched |= low <= c && c <= high;
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    if i >= pattern.len() {
+        // Unterminated class, Redis treats the rest of the pattern as the class.
+        return Some((matched != negate, i));
+    }
+    Some((matched != negate, i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    fn matches(pattern: &str, key: &str) -> bool {
+        glob_match(pattern.as_bytes(), key.as_bytes())
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("cache:*", "cache:user:1"));
+        assert!(!matches("cache:*", "session:1"));
+        assert!(matches("*:1", "cache:user:1"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("a*b*c", "axxbyyc"));
+        assert!(!matches("a*b*c", "axxbyy"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("key:[0-9]", "key:7"));
+        assert!(!matches("key:[0-9]", "key:x"));
+    }
+
+    #[test]
+    fn test_escapes_and_hash_tags() {
+        assert!(matches("{my-custom}*", "{my-custom}:1"));
+        assert!(!matches("{my-custom}*", "{my-custom-another}:1"));
+        assert!(matches(r"literal\*", "literal*"));
+        assert!(!matches(r"literal\*", "literal-"));
+    }
+}
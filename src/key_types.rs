@@ -0,0 +1,83 @@
+use crate::config::{CleanupConfig, KeyType};
+
+/// Leaves out the keys whose type is not in the `types` of a rule: with `SCAN ... TYPE` when
+/// the rule has a single type and the server supports it, with a `TYPE` call per key
+/// otherwise (`SCAN` only takes one type).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeFilter {
+    types: Vec<KeyType>,
+    scan_type: Option<KeyType>,
+}
+
+impl TypeFilter {
+    /// The filter of a rule on a node whose `INFO server` is `info`, `None` if the rule
+    /// selects keys of every type.
+    pub fn for_config(conf: &CleanupConfig, info: &str) -> Option<TypeFilter> {
+        if conf.types.is_empty() {
+            return None;
+        }
+        let mut types = conf.types.clone();
+        types.sort();
+        types.dedup();
+        let scan_type = match types[..] {
+            [only] if supports_scan_type(info) => Some(only),
+            _ => None,
+        };
+        Some(TypeFilter { types, scan_type })
+    }
+
+    /// The `TYPE` argument of `SCAN`, `None` if the types are checked key by key.
+    pub fn scan_type(&self) -> Option<KeyType> {
+        self.scan_type
+    }
+
+    pub fn types(&self) -> &[KeyType] {
+        &self.types
+    }
+
+    /// The type of a key whose `TYPE` reply is `name`, `None` if the key is left out.
+    pub fn selects(&self, name: &str) -> Option<KeyType> {
+        KeyType::from_name(name).filter(|key_type| self.types.contains(key_type))
+    }
+}
+
+/// Whether the server of `INFO server` has `SCAN ... TYPE`, added in Redis 6.0 (Valkey
+/// reports a compatible `redis_version`).
+pub fn supports_scan_type(info: &str) -> bool {
+    info.lines()
+        .find_map(|line| line.trim().strip_prefix("redis_version:"))
+        .and_then(|version| version.split('.').next()?.parse::<u32>().ok())
+        .is_some_and(|major| major >= 6)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{supports_scan_type, TypeFilter};
+    use crate::config::{CleanupConfig, KeyType};
+
+    #[test]
+    fn test_scan_type_needs_redis_6() {
+        assert!(supports_scan_type("# Server\r\nredis_version:7.2.4\r\n"));
+        assert!(!supports_scan_type("# Server\r\nredis_version:5.0.14\r\n"));
+        assert!(!supports_scan_type(""));
+    }
+
+    #[test]
+    fn test_filter_of_rule() {
+        let info = "redis_version:6.2.14\r\n";
+        let mut conf = CleanupConfig::new("rule", "session:*", 60, 10);
+        assert_eq!(TypeFilter::for_config(&conf, info), None);
+        conf.types = vec![KeyType::Hash, KeyType::Hash];
+        let single = TypeFilter::for_config(&conf, info).unwrap();
+        assert_eq!(single.scan_type(), Some(KeyType::Hash));
+        assert_eq!(single.types(), [KeyType::Hash]);
+        let old = TypeFilter::for_config(&conf, "redis_version:5.0.14\r\n").unwrap();
+        assert_eq!(old.scan_type(), None);
+        conf.types.push(KeyType::Zset);
+        let several = TypeFilter::for_config(&conf, info).unwrap();
+        assert_eq!(several.scan_type(), None);
+        assert_eq!(several.selects("zset"), Some(KeyType::Zset));
+        assert_eq!(several.selects("string"), None);
+        assert_eq!(several.selects("none"), None);
+    }
+}
@@ -0,0 +1,48 @@
+//! Sets the expiry of Redis keys based on a given pattern and time-to-live (TTL) value.
+//!
+//! The [`Cleaner`] runs a list of [`CleanupConfig`] rules and returns a [`ProcessingResult`]
+//! per rule, which can be rendered into a notification with
+//! [`render_notification_content`].
+pub mod backend;
+pub mod checkpoint;
+pub mod cleaner;
+pub mod config;
+pub mod connection;
+pub mod error;
+pub mod glob;
+pub mod key_types;
+pub mod lock;
+pub mod matcher;
+pub mod memory;
+pub mod notification;
+pub mod rate;
+pub mod redact;
+pub mod result;
+pub mod retry;
+pub mod schedule;
+pub mod throttle;
+pub mod usage;
+
+pub use backend::{Backend, RedisBackend};
+pub use checkpoint::CheckpointStore;
+pub use cleaner::{cleanup_with_backend, Cleaner};
+pub use config::{
+    load_config_file, load_configs, CleanupAction, CleanupConfig, CleanupEngine, ConfigFile,
+    KeyType, RedisOptions, SentinelOptions, TargetConfig, TlsOptions,
+};
+pub use error::{CleanerError, ErrorCategory};
+pub use key_types::TypeFilter;
+pub use lock::RunLock;
+pub use matcher::KeyMatcher;
+pub use memory::MemoryBackend;
+pub use notification::{
+    render_notification_content, send_notification, status_color, NotificationOptions,
+};
+pub use rate::{RateLimit, RateLimiter};
+pub use result::{
+    exit_code, IncompleteReason, NodeResult, ProcessingResult, ProcessingStatus, Timings,
+};
+pub use retry::RetryPolicy;
+pub use schedule::RuleSchedule;
+pub use throttle::ThrottleConfig;
+pub use usage::UsageFilter;
@@ -0,0 +1,248 @@
+use crate::config::RedisOptions;
+use crate::connection::{open_connection, RedisConnection};
+use crate::error::CleanerError;
+use log::warn;
+use redis::RedisResult;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+
+const RENEW_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+	return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return 0"#;
+
+const RELEASE_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+	return redis.call("DEL", KEYS[1])
+end
+return 0"#;
+
+/// How often the lock is retried while waiting for another holder.
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Distributed lock taken on every rule before it runs, so several instances of the cleaner
+/// pointed at the same Redis never process the same rule at the same time.
+///
+/// The lock is a `SET key token NX PX ttl` key, renewed while the rule runs and released
+/// once it finished; if the instance dies the key expires after `ttl`.
+#[derive(Debug, Clone)]
+pub enum RunLock {
+    Disabled,
+    Enabled {
+        prefix: String,
+        ttl: Duration,
+        /// How long to wait for the current holder, the rule is skipped right away if `None`.
+        wait: Option<Duration>,
+    },
+}
+
+impl RunLock {
+    /// Skips the rule if another instance holds its lock.
+    pub fn skip(prefix: &str, ttl: Duration) -> RunLock {
+        RunLock::Enabled {
+            prefix: prefix.to_string(),
+            ttl,
+            wait: None,
+        }
+    }
+
+    /// Waits up to `timeout` for another instance to release the lock, then skips the rule.
+    pub fn wait(prefix: &str, ttl: Duration, timeout: Duration) -> RunLock {
+        RunLock::Enabled {
+            prefix: prefix.to_string(),
+            ttl,
+            wait: Some(timeout),
+        }
+    }
+
+    /// Takes the lock of the rule `id`, returns `None` if another instance holds it.
+    pub async fn acquire(
+        &self,
+        options: &RedisOptions,
+        id: &str,
+    ) -> Result<Option<LockGuard>, CleanerError> {
+        let (prefix, ttl, wait) = match self {
+            RunLock::Disabled => return Ok(Some(LockGuard::default())),
+            RunLock::Enabled { prefix, ttl, wait } => (prefix, *ttl, *wait),
+        };
+        let key = format!("{}{}", prefix, id);
+        let token = lock_token();
+        let mut connection = open_connection(options).await?;
+        let deadline = Instant::now() + wait.unwrap_or_default();
+        while !try_lock(&mut connection, &key, &token, ttl).await? {
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            tokio::time::sleep(RETRY_INTERVAL.min(deadline - Instant::now())).await;
+        }
+        let (stop, mut stopped) = oneshot::channel::<()>();
+        let (lost_sender, lost) = watch::channel(false);
+        let renewal = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(ttl / 3) => {
+                        match renew(&mut connection, &key, &token, ttl).await {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                warn!("Lock {} was lost, another instance may run the rule", key);
+                                let _ = lost_sender.send(true);
+                                return;
+                            }
+                            Err(err) => warn!("Lock {} could not be renewed: {}", key, err),
+                        }
+                    }
+                    _ = &mut stopped => {
+                        if let Err(err) = release(&mut connection, &key, &token).await {
+                            warn!("Lock {} could not be released: {}", key, err);
+                        }
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Some(LockGuard {
+            stop: Some(stop),
+            renewal: Some(renewal),
+            lost: Some(lost),
+        }))
+    }
+}
+
+/// Keeps the lock renewed until it is released. A dropped guard releases the lock in the
+/// background, [`LockGuard::release`] waits until it is gone.
+#[derive(Debug, Default)]
+pub struct LockGuard {
+    stop: Option<oneshot::Sender<()>>,
+    renewal: Option<JoinHandle<()>>,
+    /// Set by the renewal once the lock was taken over, e.g. after it expired during a pause.
+    lost: Option<watch::Receiver<bool>>,
+}
+
+impl LockGuard {
+    /// True once another instance took the lock over, the rule must stop.
+    pub fn is_lost(&self) -> bool {
+        self.lost.as_ref().is_some_and(|lost| *lost.borrow())
+    }
+
+    /// A guard whose lock was already taken over.
+    #[cfg(test)]
+    pub(crate) fn lost() -> LockGuard {
+        let (_, lost) = watch::channel(true);
+        LockGuard {
+            stop: None,
+            renewal: None,
+            lost: Some(lost),
+        }
+    }
+
+    pub async fn release(mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(renewal) = self.renewal.take() {
+            let _ = renewal.await;
+        }
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// A token unique to this holder, only the holder may renew or release the lock.
+fn lock_token() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!(
+        "{}:{}:{}",
+        std::env::var("HOSTNAME").unwrap_or("localhost".to_string()),
+        std::process::id(),
+        nanos
+    )
+}
+
+async fn try_lock(
+    connection: &mut RedisConnection,
+    key: &str,
+    token: &str,
+    ttl: Duration,
+) -> RedisResult<bool> {
+    let reply: Option<String> = redis::cmd("SET")
+        .arg(key)
+        .arg(token)
+        .arg("NX")
+        .arg("PX")
+        .arg(ttl.as_millis() as u64)
+        .query_async(connection)
+        .await?;
+    Ok(reply.is_some())
+}
+
+async fn renew(
+    connection: &mut RedisConnection,
+    key: &str,
+    token: &str,
+    ttl: Duration,
+) -> RedisResult<bool> {
+    let renewed: i64 = redis::Script::new(RENEW_SCRIPT)
+        .key(key)
+        .arg(token)
+        .arg(ttl.as_millis() as u64)
+        .invoke_async(connection)
+        .await?;
+    Ok(renewed == 1)
+}
+
+async fn release(connection: &mut RedisConnection, key: &str, token: &str) -> RedisResult<()> {
+    let _: i64 = redis::Script::new(RELEASE_SCRIPT)
+        .key(key)
+        .arg(token)
+        .invoke_async(connection)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RunLock;
+    use crate::config::{RedisOptions, TlsOptions};
+    use std::time::Duration;
+
+    fn unreachable() -> RedisOptions {
+        RedisOptions {
+            protocol: "redis".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: "1".to_string(),
+            username: String::new(),
+            password: String::new(),
+            db: 0,
+            cluster: false,
+            sentinel: None,
+            tls: TlsOptions::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disabled_lock_needs_no_connection() {
+        let guard = RunLock::Disabled
+            .acquire(&unreachable(), "rule")
+            .await
+            .unwrap();
+        assert!(guard.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_lock_is_a_connection_error() {
+        let lock = RunLock::skip("redis-cleaner:lock:", Duration::from_secs(30));
+        let err = lock.acquire(&unreachable(), "rule").await.unwrap_err();
+        assert_eq!(err.exit_code(), 4);
+    }
+}
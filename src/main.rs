@@ -1,43 +1,17 @@
-extern crate tera;
 use clap::Parser;
 use dotenv::dotenv;
-use log::info;
-use redis::Client;
-use serde::{Deserialize, Serialize};
-use serde_yaml::from_reader;
-use std::env;
-use std::error::Error;
-use tera::{Context, Tera};
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct RequestData {
-    attachments: Vec<Attachment>,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Attachment {
-    title: String,
-    text: String,
-    color: String,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-struct CleanupConfig {
-    pub name: String,
-    pub pattern: String,
-    pub ttl_seconds: i64,
-    pub batch: i64,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct ProcessingResult {
-    config: CleanupConfig,
-    processed_keys: i64,
-    iterations: i64,
-    error_msg: String,
-    execution_time: String,
-}
+use log::{error, info, warn};
+use redis_cleaner::config::max_concurrent_rules_from_env;
+use redis_cleaner::notification::COLOR_FAILED;
+use redis_cleaner::redact::redact;
+use redis_cleaner::{
+    exit_code, load_config_file, render_notification_content, send_notification, status_color,
+    CheckpointStore, Cleaner, CleanerError, IncompleteReason, NotificationOptions,
+    ProcessingResult, ProcessingStatus, RateLimit, RedisOptions, RetryPolicy, RunLock,
+};
+use std::collections::BTreeMap;
+use std::io::Write;
+use tokio::sync::mpsc::unbounded_channel;
 
 #[derive(Parser, Debug)]
 #[clap(version = "1.0", author = "Oliver Szabo <oleewere@gmail.com>")]
@@ -46,195 +20,233 @@ struct Args {
     config: String,
     #[clap(short, long)]
     dry_run: bool,
+    /// Keep running and execute every rule on its `schedule`.
+    #[clap(long)]
+    daemon: bool,
 }
 
-fn render_notification_content(
-    file: &str,
-    results: Vec<ProcessingResult>,
-    tera_glob: &str,
-) -> String {
-    let tera = Tera::new(tera_glob).unwrap();
-    let mut context = Context::new();
-    context.insert("results", &results);
-    return tera.render(file, &context).unwrap();
+fn build_cleaner(args: &Args) -> Result<Cleaner, CleanerError> {
+    let config = load_config_file(&args.config)?;
+    let mut targets = BTreeMap::new();
+    for (name, target) in config.targets.iter() {
+        targets.insert(name.clone(), target.to_options(name)?);
+    }
+    // The REDIS_* variables are only needed by the rules without a target.
+    let cleaner = match config.rules.iter().any(|rule| rule.target.is_none()) {
+        true => targets.into_iter().fold(
+            Cleaner::new(RedisOptions::from_env()?, config.rules),
+            |cleaner, (name, options)| cleaner.target(&name, options),
+        ),
+        false => Cleaner::with_targets(targets, config.rules),
+    };
+    Ok(cleaner
+        .checkpoint_store(CheckpointStore::from_env()?)
+        .run_lock(RunLock::from_env()?)
+        .retry_policy(RetryPolicy::from_env()?)
+        .rate_limit(RateLimit::from_env()?)
+        .max_concurrent_rules(max_concurrent_rules_from_env()?)
+        .dry_run(args.dry_run))
 }
 
-fn create_redis_client(
-    protocol: &str,
-    host: &str,
-    port: &str,
-    username: &str,
-    password: &str,
-) -> Client {
-    let connection_url = format!(
-        "{}://{}:{}@{}:{}/",
-        protocol, username, password, host, port
-    );
-    Client::open(connection_url).unwrap()
+async fn run(args: &Args) -> Result<Vec<ProcessingResult>, CleanerError> {
+    build_cleaner(args)?.run().await
 }
 
-fn expire_keys(
-    client: &Client,
-    conf: &CleanupConfig,
-    dry_run: bool,
-) -> (Option<Box<dyn Error>>, i64, i64) {
-    let mut connection = client.get_connection().unwrap();
-    const LUA_SCRIPT: &str = r###"
-	local match = ARGV[1];
-	local count = tonumber(ARGV[2]);
-	local expire_num = tonumber(ARGV[3]);
-	local dry_run = tonumber(ARGV[4]);
-	local iterations = 0;
-	local max_iterations = 100000;
-	local processed = 0;
-	local cursor = "0";
-	repeat
-		iterations = iterations + 1;
-		local result = redis.call("SCAN", cursor, "MATCH", match, "COUNT", count);
-		for _, v in ipairs(result[2]) do
-			local ttl = redis.call("TTL", v)
-			if ttl == -1 then
-				processed = processed + 1;
-				if dry_run == 0 then
-        			redis.call("EXPIRE", v, expire_num);
-				end
-			end
-		end
-		if iterations < max_iterations then
-			cursor = result[1];
-		else
-			cursor = "0";
-		end
-	until cursor == "0";
-	local ret = {processed, iterations}
-	return ret"###;
-    let script = redis::Script::new(LUA_SCRIPT);
-    let dry_run_num = match dry_run {
-        true => 1,
-        false => 0,
-    };
-    let result = script
-        .key(conf.pattern.clone())
-        .arg(conf.pattern.clone())
-        .arg(conf.batch.clone())
-        .arg(conf.ttl_seconds.clone())
-        .arg(dry_run_num)
-        .invoke::<(i64, i64)>(&mut connection);
-    let (processed, iterations) = match result {
-        Ok(v) => v,
-        Err(err) => {
-            return (Some(Box::new(err)), 0, 0);
+/// Runs the scheduled rules until the process is stopped, with a notification per run.
+async fn run_daemon(args: &Args, notification: &NotificationOptions) -> Result<(), CleanerError> {
+    let cleaner = build_cleaner(args)?;
+    let (sender, mut receiver) = unbounded_channel();
+    let daemon = tokio::spawn(async move { cleaner.run_daemon(sender).await });
+    loop {
+        tokio::select! {
+            result = receiver.recv() => match result {
+                Some(result) => {
+                    let results = vec![result];
+                    report_results(&results);
+                    let (text, color, _) = notification_content(notification, results);
+                    notify(notification, text, color).await;
+                }
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => {
+                info!("Stopping the daemon");
+                return Ok(());
+            }
         }
-    };
-    return (None, processed, iterations);
+    }
+    match daemon.await {
+        Ok(result) => result,
+        Err(err) => Err(CleanerError::Config(err.to_string())),
+    }
 }
 
-async fn cleanup(client: Client, conf: CleanupConfig, dry_run: bool) -> ProcessingResult {
-    let start = std::time::Instant::now();
-    let duration = start.elapsed();
-    let (error, processed_keys, iterations) = expire_keys(&client, &conf, dry_run);
-    ProcessingResult {
-        config: conf,
-        processed_keys,
-        iterations,
-        error_msg: error
-            .as_ref()
-            .map(|e| e.to_string())
-            .unwrap_or_else(|| "".to_string()),
-        execution_time: format!("{:?}", duration),
+/// The notification text and color of a finished run, with the exit code of the run. The
+/// template is only rendered if the notification is enabled.
+fn notification_content(
+    notification: &NotificationOptions,
+    results: Vec<ProcessingResult>,
+) -> (String, &'static str, i32) {
+    let exit_code = exit_code(&results);
+    let color = status_color(&results);
+    if !notification.is_enabled() {
+        return (String::new(), color, exit_code);
+    }
+    match render_notification_content(&notification.template_file, results, "*.j2") {
+        Ok(text) => (text, color, exit_code),
+        Err(err) => {
+            error!("{}", err);
+            let code = match exit_code {
+                0 => err.exit_code(),
+                code => code,
+            };
+            (format!("Cleanup finished, but {}", err), COLOR_FAILED, code)
+        }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    dotenv().ok();
-    env_logger::init();
-    let args = Args::parse();
-    let redis_host = env::var("REDIS_HOST").unwrap();
-    let redis_port = env::var("REDIS_PORT").unwrap();
-    let redis_username = env::var("REDIS_USERNAME").unwrap_or("".to_string());
-    let redis_password = env::var("REDIS_PASSWORD").unwrap_or("".to_string());
-    let redis_protocol = env::var("REDIS_PROTOCOL").unwrap_or("rediss".to_string());
-    let webhook_url = env::var("NOTIFICATION_WEBHOOK_URL").unwrap_or("".to_string());
-    let cleanup_title =
-        env::var("NOTIFICATION_CLEANUP_TITLE").unwrap_or("Redis Cleanup".to_string());
-    let notification_template_file =
-        env::var("NOTIFICATION_TEMPALTE_FILE").unwrap_or("notification.j2".to_string());
-    let config_file = args.config;
-    let dry_run = args.dry_run;
-    let conf_file = std::fs::File::open(config_file).unwrap();
-    let configs: Vec<CleanupConfig> = from_reader(conf_file).unwrap();
-    let redis_client = create_redis_client(
-        &redis_protocol,
-        &redis_host,
-        &redis_port,
-        &redis_username,
-        &redis_password,
-    );
-    info!("Dry run: {}", dry_run);
-    let mut handles = Vec::new();
-    let task_count = configs.len();
-    for i in 0..task_count {
-        let job = tokio::spawn(cleanup(redis_client.clone(), configs[i].clone(), dry_run));
-        handles.push(job);
+/// Sends the notification if it is enabled, returns the exit code of the failure.
+async fn notify(notification: &NotificationOptions, text: String, color: &str) -> Option<i32> {
+    if !notification.is_enabled() {
+        return None;
+    }
+    match send_notification(notification, text, color).await {
+        Ok(()) => None,
+        Err(err) => {
+            error!("{}", err);
+            Some(err.exit_code())
+        }
     }
-    let mut results = Vec::new();
-    for job in handles {
-        results.push(job.await.unwrap());
+}
+
+/// Why the scan of an incomplete rule stopped, with the limit it ran into.
+fn incomplete_reason(res: &ProcessingResult) -> String {
+    match res.incomplete_reason {
+        Some(IncompleteReason::ReplicaWaitExceeded) => format!(
+            "the replicas were still behind after maxReplicaWaitMs ({} ms)",
+            res.config.max_replica_wait_ms
+        ),
+        Some(IncompleteReason::ThrottleWaitExceeded) => format!(
+            "the server was still overloaded after maxWaitMs ({} ms)",
+            res.config
+                .throttle
+                .as_ref()
+                .map(|throttle| throttle.max_wait_ms)
+                .unwrap_or_default()
+        ),
+        Some(IncompleteReason::LockLost) => "the lock was lost".to_string(),
+        Some(IncompleteReason::MaxIterations) | None => {
+            format!("maxIterations ({}) was reached", res.config.max_iterations)
+        }
     }
-    let mut color = "#2EB67D";
-    for res in results.clone() {
-        if res.error_msg.trim().is_empty() {
+}
+
+fn report_results(results: &[ProcessingResult]) {
+    for res in results.iter() {
+        if res.status == ProcessingStatus::Skipped {
+            warn!(
+                "{} - Skipped: locked, another instance is running the rule",
+                res.config.name
+            );
+        } else if res.status == ProcessingStatus::Incomplete {
+            warn!(
+                "{} - Scan is incomplete, {}; stopped after {} iterations, processed keys: {}",
+                res.config.name,
+                incomplete_reason(res),
+                res.iterations,
+                res.processed_keys
+            );
+        } else if res.status == ProcessingStatus::Success {
             info!(
-                "{} - Number of processed Keys: {}",
-                res.config.name, res.processed_keys
+                "{} - Number of processed Keys: {} (ttl added: {}, ttl shortened: {}, deleted: {}, persisted: {}, excluded: {})",
+                res.config.name,
+                res.processed_keys,
+                res.ttl_added,
+                res.ttl_shortened,
+                res.deleted,
+                res.persisted,
+                res.excluded_keys
             );
             info!("{} - Iterations: {}", res.config.name, res.iterations);
-        } else {
-            color = "#E01E5A";
             info!(
+                "{} - Resumed: {}, completed: {}",
+                res.config.name, res.resumed, res.completed
+            );
+            if res.nodes.len() > 1 {
+                for node in res.nodes.iter() {
+                    info!(
+                        "{} - Node {}: processed keys: {}, iterations: {}",
+                        res.config.name, node.node, node.processed_keys, node.iterations
+                    );
+                }
+            }
+        } else {
+            error!(
                 "Error setting expire time for keys with name '{}' and match: {} - {}",
                 res.config.name, res.config.pattern, res.error_msg
             );
         }
     }
-    if !webhook_url.is_empty() {
-        let text_content =
-            render_notification_content(notification_template_file.as_str(), results, "*.j2");
-        let attachment = Attachment {
-            text: text_content,
-            title: cleanup_title.clone(),
-            color: color.to_string(),
-        };
-        let attachments = vec![attachment];
-        let request_data = RequestData {
-            attachments: attachments,
-        };
-        let body = serde_json::to_string(&request_data).unwrap();
-        let client = reqwest::Client::new();
-        let res = client
-            .post(webhook_url)
-            .header("Content-type", "application/json")
-            .body(body)
-            .send()
+}
+
+/// The default format of env_logger, with the secrets redacted from every message.
+fn init_logger() {
+    env_logger::Builder::from_default_env()
+        .format(|buf, record| {
+            writeln!(
+                buf,
+                "[{} {:<5} {}] {}",
+                buf.timestamp(),
+                buf.default_styled_level(record.level()),
+                record.target(),
+                redact(&record.args().to_string())
+            )
+        })
+        .init();
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv().ok();
+    init_logger();
+    let args = Args::parse();
+    let notification = match NotificationOptions::from_env() {
+        Ok(notification) => notification,
+        Err(err) => {
+            error!("Cleanup could not start: {}", err);
+            std::process::exit(err.exit_code());
+        }
+    };
+    if args.daemon {
+        if let Err(err) = run_daemon(&args, &notification).await {
+            error!("Daemon stopped: {}", err);
+            notify(
+                &notification,
+                format!("Daemon stopped: {}", err),
+                COLOR_FAILED,
+            )
             .await;
-        match res {
-            Ok(result) => {
-                let status = result.status();
-                if status.clone().is_success() {
-                    info!("Notification has been sent successfully.");
-                } else {
-                    let code = status.clone().as_u16();
-                    info!(
-                        "Notification error: {} - code: {}",
-                        result.text().await.unwrap(),
-                        code
-                    );
-                }
-            }
-            Err(err) => {
-                info!("Notification error: {}", err.to_string());
-            }
+            std::process::exit(err.exit_code());
+        }
+        std::process::exit(0);
+    }
+    let (text, color, mut exit_code) = match run(&args).await {
+        Ok(results) => {
+            report_results(&results);
+            notification_content(&notification, results)
+        }
+        Err(err) => {
+            error!("Cleanup could not start: {}", err);
+            (
+                format!("Cleanup could not start: {}", err),
+                COLOR_FAILED,
+                err.exit_code(),
+            )
+        }
+    };
+    if let Some(code) = notify(&notification, text, color).await {
+        if exit_code == 0 {
+            exit_code = code;
         }
     }
+    std::process::exit(exit_code);
 }
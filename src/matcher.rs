@@ -0,0 +1,160 @@
+use crate::config::{CleanupConfig, CleanupEngine};
+use crate::error::CleanerError;
+use crate::glob::glob_match;
+use regex::bytes::Regex;
+
+/// The keys of a rule: its `pattern` and `include` globs, minus the `exclude` globs, and only
+/// the keys matching `regex` if set.
+///
+/// A single `SCAN` walk covers all the include globs, with `MATCH` on the literal prefix
+/// they share, the globs, the excludes and the regex are then checked on the returned keys.
+#[derive(Debug, Clone)]
+pub struct KeyMatcher {
+    scan_pattern: String,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    regex: Option<Regex>,
+}
+
+impl KeyMatcher {
+    pub fn for_config(conf: &CleanupConfig) -> Result<KeyMatcher, CleanerError> {
+        let invalid = |msg: String| CleanerError::Config(format!("rule '{}': {}", conf.name, msg));
+        let filtered = !conf.include.is_empty() || !conf.exclude.is_empty();
+        if conf.engine == CleanupEngine::Lua && (filtered || conf.regex.is_some()) {
+            return Err(invalid(
+                "include, exclude and regex need the scan engine".to_string(),
+            ));
+        }
+        let regex = match &conf.regex {
+            Some(regex) => {
+                Some(Regex::new(regex).map_err(|e| invalid(format!("invalid regex: {}", e)))?)
+            }
+            None => None,
+        };
+        let mut include = vec![conf.pattern.clone()];
+        include.extend(conf.include.iter().cloned());
+        Ok(KeyMatcher {
+            scan_pattern: scan_pattern(&include),
+            include,
+            exclude: conf.exclude.clone(),
+            regex,
+        })
+    }
+
+    /// The `MATCH` glob of `SCAN`.
+    pub fn scan_pattern(&self) -> &str {
+        &self.scan_pattern
+    }
+
+    /// The keys of the rule among the keys returned by `SCAN`, and how many of them the
+    /// `exclude` globs and the regex left out.
+    pub fn select(&self, keys: Vec<Vec<u8>>) -> (Vec<Vec<u8>>, i64) {
+        let mut excluded = 0;
+        let selected = keys
+            .into_iter()
+            .filter(|key| self.includes(key))
+            .filter(|key| {
+                let kept = !self
+                    .exclude
+                    .iter()
+                    .any(|glob| glob_match(glob.as_bytes(), key))
+                    && self.regex.as_ref().is_none_or(|regex| regex.is_match(key));
+                if !kept {
+                    excluded += 1;
+                }
+                kept
+            })
+            .collect();
+        (selected, excluded)
+    }
+
+    fn includes(&self, key: &[u8]) -> bool {
+        // SCAN already matched the only glob.
+        self.include.len() == 1
+            || self
+                .include
+                .iter()
+                .any(|glob| glob_match(glob.as_bytes(), key))
+    }
+}
+
+/// The glob itself for a single glob, otherwise the literal prefix shared by all the globs
+/// followed by `*`.
+fn scan_pattern(globs: &[String]) -> String {
+    if let [glob] = globs {
+        return glob.clone();
+    }
+    let literal = |glob: &String| -> String {
+        glob.chars()
+            .take_while(|c| !matches!(c, '*' | '?' | '[' | '\\'))
+            .collect()
+    };
+    let mut prefix = literal(&globs[0]);
+    for glob in globs[1..].iter() {
+        let shared: String = prefix
+            .chars()
+            .zip(literal(glob).chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(c, _)| c)
+            .collect();
+        prefix = shared;
+    }
+    prefix + "*"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyMatcher;
+    use crate::config::{CleanupConfig, CleanupEngine};
+
+    fn keys(keys: &[&str]) -> Vec<Vec<u8>> {
+        keys.iter().map(|key| key.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_one_walk_for_all_includes() {
+        let mut conf = CleanupConfig::new("rule", "cache:user:*", 60, 10);
+        conf.engine = CleanupEngine::Scan;
+        assert_eq!(
+            KeyMatcher::for_config(&conf).unwrap().scan_pattern(),
+            "cache:user:*"
+        );
+        conf.include = vec!["cache:item:*".to_string(), "cache:u[0-9]*".to_string()];
+        let matcher = KeyMatcher::for_config(&conf).unwrap();
+        assert_eq!(matcher.scan_pattern(), "cache:*");
+        let (selected, excluded) = matcher.select(keys(&[
+            "cache:user:1",
+            "cache:item:2",
+            "cache:u7",
+            "cache:config",
+        ]));
+        assert_eq!(
+            selected,
+            keys(&["cache:user:1", "cache:item:2", "cache:u7"])
+        );
+        assert_eq!(excluded, 0);
+        conf.include = vec!["session:*".to_string()];
+        let matcher = KeyMatcher::for_config(&conf).unwrap();
+        assert_eq!(matcher.scan_pattern(), "*");
+    }
+
+    #[test]
+    fn test_excludes_and_regex() {
+        let mut conf = CleanupConfig::new("rule", "cache:*", 60, 10);
+        conf.engine = CleanupEngine::Scan;
+        conf.exclude = vec!["cache:config:*".to_string()];
+        conf.regex = Some("^cache:[a-z:]+[0-9]+$".to_string());
+        let matcher = KeyMatcher::for_config(&conf).unwrap();
+        let (selected, excluded) =
+            matcher.select(keys(&["cache:user:1", "cache:config:1", "cache:user:tmp"]));
+        assert_eq!(selected, keys(&["cache:user:1"]));
+        assert_eq!(excluded, 2);
+
+        conf.regex = Some("(".to_string());
+        let err = KeyMatcher::for_config(&conf).unwrap_err();
+        assert!(err.to_string().contains("invalid regex"), "{}", err);
+        conf.regex = None;
+        conf.engine = CleanupEngine::Lua;
+        assert_eq!(KeyMatcher::for_config(&conf).unwrap_err().exit_code(), 3);
+    }
+}
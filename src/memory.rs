@@ -0,0 +1,299 @@
+use crate::backend::Backend;
+use crate::config::KeyType;
+use crate::glob::glob_match;
+use redis::{ErrorKind, RedisError, RedisResult};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    key_type: String,
+    expires_at: Option<u64>,
+    idle_seconds: i64,
+    frequency: i64,
+}
+
+/// In-memory [`Backend`] with glob matching and TTLs, for tests and dry experiments.
+///
+/// Time does not pass by itself, it is moved forward with [`MemoryBackend::advance`].
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    entries: BTreeMap<Vec<u8>, Entry>,
+    now: u64,
+    failure: Option<(ErrorKind, &'static str)>,
+    /// How many operations fail before the backend recovers, `None` if it never does.
+    failures_left: Option<u32>,
+    /// The next key to visit of every cursor returned by `SCAN`, the cursor is its position.
+    cursors: Vec<Vec<u8>>,
+    /// The answers of the next `INFO` calls (whatever the section), the last one is repeated.
+    info: Vec<String>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    /// Adds a `string` key without TTL.
+    pub fn insert(&mut self, key: &str) {
+        self.insert_typed(key, "string", None);
+    }
+
+    /// Adds a `string` key expiring in `ttl_seconds`.
+    pub fn insert_with_ttl(&mut self, key: &str, ttl_seconds: u64) {
+        self.insert_typed(key, "string", Some(ttl_seconds));
+    }
+
+    pub fn insert_typed(&mut self, key: &str, key_type: &str, ttl_seconds: Option<u64>) {
+        self.entries.insert(
+            key.as_bytes().to_vec(),
+            Entry {
+                key_type: key_type.to_string(),
+                expires_at: ttl_seconds.map(|ttl| self.now + ttl),
+                idle_seconds: 0,
+                frequency: 0,
+            },
+        );
+    }
+
+    /// Sets what `OBJECT IDLETIME` and `OBJECT FREQ` return for the key.
+    pub fn set_usage(&mut self, key: &str, idle_seconds: i64, frequency: i64) {
+        if let Some(entry) = self.entries.get_mut(key.as_bytes()) {
+            entry.idle_seconds = idle_seconds;
+            entry.frequency = frequency;
+        }
+    }
+
+    /// Moves the clock forward, keys whose TTL elapsed disappear.
+    pub fn advance(&mut self, seconds: u64) {
+        self.now += seconds;
+        let now = self.now;
+        self.entries
+            .retain(|_, entry| entry.expires_at.map(|at| at > now).unwrap_or(true));
+    }
+
+    /// Makes every following operation fail with the given error.
+    pub fn fail_with(&mut self, kind: ErrorKind, message: &'static str) {
+        self.failure = Some((kind, message));
+        self.failures_left = None;
+    }
+
+    /// Makes the next `times` operations fail with the given error, like a transient outage.
+    pub fn fail_times(&mut self, kind: ErrorKind, message: &'static str, times: u32) {
+        self.failure = Some((kind, message));
+        self.failures_left = Some(times);
+    }
+
+    /// Adds an answer of `INFO`, e.g. `instantaneous_ops_per_sec:50000` to simulate load.
+    pub fn queue_info(&mut self, info: &str) {
+        self.info.push(info.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Same as the `TTL` command: `-2` for a missing key, `-1` for a key without TTL.
+    pub fn ttl_of(&self, key: &str) -> i64 {
+        self.key_ttl(key.as_bytes())
+    }
+
+    fn key_ttl(&self, key: &[u8]) -> i64 {
+        match self.entries.get(key) {
+            None => -2,
+            Some(Entry {
+                expires_at: None, ..
+            }) => -1,
+            Some(Entry {
+                expires_at: Some(at),
+                ..
+            }) => (at - self.now) as i64,
+        }
+    }
+
+    fn check(&mut self) -> RedisResult<()> {
+        let (kind, message) = match self.failure {
+            Some(failure) => failure,
+            None => return Ok(()),
+        };
+        match self.failures_left {
+            Some(0) => {
+                self.failure = None;
+                return Ok(());
+            }
+            Some(left) => self.failures_left = Some(left - 1),
+            None => {}
+        }
+        Err(RedisError::from((kind, message)))
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn address(&self) -> String {
+        "memory".to_string()
+    }
+
+    /// The cursor points at the next key to visit in the sorted keyspace, so keys removed
+    /// during the walk do not make it skip others. Like `SCAN` the `COUNT` limits the
+    /// visited keys, not the returned ones.
+    async fn scan(
+        &mut self,
+        cursor: u64,
+        pattern: &str,
+        count: i64,
+        key_type: Option<KeyType>,
+    ) -> RedisResult<(u64, Vec<Vec<u8>>)> {
+        self.check()?;
+        let count = count.max(1) as usize;
+        let mut visited: Vec<Vec<u8>> = match cursor {
+            0 => self.entries.keys().take(count + 1).cloned().collect(),
+            cursor => {
+                let from = self.cursors.get(cursor as usize - 1).ok_or_else(|| {
+                    RedisError::from((ErrorKind::ResponseError, "invalid cursor"))
+                })?;
+                self.entries
+                    .range::<Vec<u8>, _>(from..)
+                    .take(count + 1)
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            }
+        };
+        let next_cursor = match visited.len() > count {
+            true => {
+                self.cursors.extend(visited.pop());
+                self.cursors.len() as u64
+            }
+            false => 0,
+        };
+        let keys = visited
+            .into_iter()
+            .filter(|key| glob_match(pattern.as_bytes(), key))
+            .filter(|key| match key_type {
+                Some(key_type) => self.entries[key].key_type == key_type.as_str(),
+                None => true,
+            })
+            .collect();
+        Ok((next_cursor, keys))
+    }
+
+    async fn ttl(&mut self, keys: &[Vec<u8>]) -> RedisResult<Vec<i64>> {
+        self.check()?;
+        Ok(keys.iter().map(|key| self.key_ttl(key)).collect())
+    }
+
+    async fn expire(&mut self, keys: &[Vec<u8>], seconds: i64) -> RedisResult<()> {
+        self.check()?;
+        for key in keys.iter() {
+            if seconds <= 0 {
+                self.entries.remove(key);
+            } else if let Some(entry) = self.entries.get_mut(key) {
+                entry.expires_at = Some(self.now + seconds as u64);
+            }
+        }
+        Ok(())
+    }
+
+    async fn unlink(&mut self, keys: &[Vec<u8>]) -> RedisResult<()> {
+        self.check()?;
+        for key in keys.iter() {
+            self.entries.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn persist(&mut self, keys: &[Vec<u8>]) -> RedisResult<()> {
+        self.check()?;
+        for key in keys.iter() {
+            if let Some(entry) = self.entries.get_mut(key) {
+                entry.expires_at = None;
+            }
+        }
+        Ok(())
+    }
+
+    async fn object(
+        &mut self,
+        subcommand: &str,
+        keys: &[Vec<u8>],
+    ) -> RedisResult<Vec<Option<i64>>> {
+        self.check()?;
+        let metric = |entry: &Entry| match subcommand {
+            "IDLETIME" => Ok(entry.idle_seconds),
+            "FREQ" => Ok(entry.frequency),
+            _ => Err(RedisError::from((
+                ErrorKind::ResponseError,
+                "unknown OBJECT subcommand",
+            ))),
+        };
+        keys.iter()
+            .map(|key| self.entries.get(key).map(metric).transpose())
+            .collect()
+    }
+
+    async fn key_type(&mut self, key: &[u8]) -> RedisResult<String> {
+        self.check()?;
+        Ok(self
+            .entries
+            .get(key)
+            .map(|entry| entry.key_type.clone())
+            .unwrap_or("none".to_string()))
+    }
+
+    async fn ping(&mut self) -> RedisResult<()> {
+        self.check()
+    }
+
+    async fn info(&mut self, _section: &str) -> RedisResult<String> {
+        self.check()?;
+        match self.info.len() {
+            0 => Ok(String::new()),
+            1 => Ok(self.info[0].clone()),
+            _ => Ok(self.info.remove(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryBackend;
+    use crate::backend::Backend;
+
+    #[tokio::test]
+    async fn test_scan_visits_every_key_once() {
+        let mut backend = MemoryBackend::new();
+        for i in 0..25 {
+            backend.insert(&format!("cache:{:02}", i));
+            backend.insert(&format!("session:{:02}", i));
+        }
+        let mut cursor = 0;
+        let mut found = Vec::new();
+        loop {
+            let (next, keys) = backend.scan(cursor, "cache:*", 7, None).await.unwrap();
+            found.extend(keys);
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(found.len(), 25);
+        assert!(found.iter().all(|key| key.starts_with(b"cache:")));
+    }
+
+    #[tokio::test]
+    async fn test_ttl_and_expiry() {
+        let mut backend = MemoryBackend::new();
+        backend.insert("persistent");
+        backend.insert_with_ttl("volatile", 10);
+        assert_eq!(backend.ttl_of("persistent"), -1);
+        assert_eq!(backend.ttl_of("volatile"), 10);
+        assert_eq!(backend.ttl_of("missing"), -2);
+        backend.expire(&[b"persistent".to_vec()], 5).await.unwrap();
+        backend.advance(6);
+        assert_eq!(backend.ttl_of("persistent"), -2);
+        assert_eq!(backend.ttl_of("volatile"), 4);
+        assert_eq!(backend.len(), 1);
+    }
+}
@@ -0,0 +1,286 @@
+use crate::config::secret_env;
+use crate::error::CleanerError;
+use crate::redact::{self, redact};
+use crate::result::{ProcessingResult, ProcessingStatus};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::env;
+use tera::{Context, Tera};
+
+pub const COLOR_SUCCESS: &str = "#2EB67D";
+pub const COLOR_INCOMPLETE: &str = "#ECB22E";
+pub const COLOR_FAILED: &str = "#E01E5A";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RequestData {
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Attachment {
+    title: String,
+    text: String,
+    color: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct NotificationOptions {
+    pub webhook_url: String,
+    pub title: String,
+    pub template_file: String,
+}
+
+impl NotificationOptions {
+    /// The webhook URL can be read from a file with `NOTIFICATION_WEBHOOK_URL_FILE`.
+    pub fn from_env() -> Result<NotificationOptions, CleanerError> {
+        let webhook_url = secret_env("NOTIFICATION_WEBHOOK_URL")?.unwrap_or_default();
+        redact::register_url(&webhook_url);
+        Ok(NotificationOptions {
+            webhook_url,
+            title: env::var("NOTIFICATION_CLEANUP_TITLE").unwrap_or("Redis Cleanup".to_string()),
+            // An empty value (like in `.env.template`) means the default template.
+            template_file: env::var("NOTIFICATION_TEMPALTE_FILE")
+                .ok()
+                .filter(|file| !file.trim().is_empty())
+                .unwrap_or("notification.j2".to_string()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.webhook_url.is_empty()
+    }
+}
+
+/// The results of the rules of one target, available as `targets` in the template.
+#[derive(Serialize, Debug)]
+struct TargetResults<'a> {
+    target: &'a str,
+    results: Vec<&'a ProcessingResult>,
+}
+
+/// Groups the results by target, in the order the targets first appear in the rules.
+fn group_by_target(results: &[ProcessingResult]) -> Vec<TargetResults<'_>> {
+    let mut groups: Vec<TargetResults<'_>> = Vec::new();
+    for result in results.iter() {
+        let target = result.config.target.as_deref().unwrap_or("default");
+        match groups.iter_mut().find(|group| group.target == target) {
+            Some(group) => group.results.push(result),
+            None => groups.push(TargetResults {
+                target,
+                results: vec![result],
+            }),
+        }
+    }
+    groups
+}
+
+pub fn render_notification_content(
+    file: &str,
+    results: Vec<ProcessingResult>,
+    tera_glob: &str,
+) -> Result<String, CleanerError> {
+    let tera = Tera::new(tera_glob)?;
+    let mut context = Context::new();
+    context.insert("targets", &group_by_target(&results));
+    context.insert("results", &results);
+    Ok(redact(&tera.render(file, &context)?))
+}
+
+/// The attachment color of the notification: the worst status of all rules wins.
+pub fn status_color(results: &[ProcessingResult]) -> &'static str {
+    if results
+        .iter()
+        .any(|res| res.status == ProcessingStatus::Failed)
+    {
+        COLOR_FAILED
+    } else if results
+        .iter()
+        .any(|res| res.status == ProcessingStatus::Incomplete)
+    {
+        COLOR_INCOMPLETE
+    } else {
+        COLOR_SUCCESS
+    }
+}
+
+pub async fn send_notification(
+    options: &NotificationOptions,
+    text: String,
+    color: &str,
+) -> Result<(), CleanerError> {
+    let attachment = Attachment {
+        text: redact(&text),
+        title: options.title.clone(),
+        color: color.to_string(),
+    };
+    let attachments = vec![attachment];
+    let request_data = RequestData { attachments };
+    let body = serde_json::to_string(&request_data)
+        .map_err(|e| CleanerError::Notification(e.to_string()))?;
+    let client = reqwest::Client::new();
+    let result = client
+        .post(&options.webhook_url)
+        .header("Content-type", "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| CleanerError::Notification(redact(&e.to_string())))?;
+    let status = result.status();
+    if !status.is_success() {
+        let text = result.text().await.unwrap_or_default();
+        return Err(CleanerError::Notification(format!(
+            "{} - code: {}",
+            redact(&text),
+            status.as_u16()
+        )));
+    }
+    info!("Notification has been sent successfully.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_notification_content, status_color, COLOR_INCOMPLETE, COLOR_SUCCESS};
+    use crate::checkpoint::CheckpointStore;
+    use crate::cleaner::cleanup_with_backend;
+    use crate::config::{CleanupAction, CleanupConfig, CleanupEngine, KeyType};
+    use crate::memory::MemoryBackend;
+    use crate::result::{IncompleteReason, ProcessingStatus};
+    use crate::retry::RetryPolicy;
+    use std::collections::BTreeMap;
+
+    async fn run(max_iterations: i64) -> Vec<crate::result::ProcessingResult> {
+        let mut backend = MemoryBackend::new();
+        for i in 0..20 {
+            backend.insert(&format!("{{my-custom}}:{}", i));
+        }
+        let conf = CleanupConfig {
+            engine: CleanupEngine::Scan,
+            max_iterations,
+            ..CleanupConfig::new("My Custom keys", "{my-custom}*", 86400, 5)
+        };
+        vec![
+            cleanup_with_backend(
+                &mut backend,
+                &CheckpointStore::Disabled,
+                &RetryPolicy::default(),
+                &conf,
+                false,
+            )
+            .await,
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_render_default_template() {
+        let text = render_notification_content("notification.j2", run(100).await, "*.j2").unwrap();
+        assert!(text.contains("My Custom keys :white_check_mark:"));
+        assert!(text.contains("Set expiration (number of keys): 20"));
+        assert!(!text.contains("Shortened TTL"));
+        assert!(text.contains("Number of batches: 4"));
+    }
+
+    #[tokio::test]
+    async fn test_render_delete_action() {
+        let mut results = run(100).await;
+        results[0].config.action = CleanupAction::Delete;
+        results[0].deleted = 20;
+        let text = render_notification_content("notification.j2", results, "*.j2").unwrap();
+        assert!(text.contains("Deleted (number of keys): 20"));
+        assert!(!text.contains("Set expiration"));
+    }
+
+    #[tokio::test]
+    async fn test_render_counts_by_type() {
+        let mut results = run(100).await;
+        assert!(
+            !render_notification_content("notification.j2", results.clone(), "*.j2")
+                .unwrap()
+                .contains("By type")
+        );
+        results[0].config.types = vec![KeyType::Hash, KeyType::String];
+        results[0].keys_by_type = BTreeMap::from([(KeyType::Hash, 12), (KeyType::String, 8)]);
+        let text = render_notification_content("notification.j2", results, "*.j2").unwrap();
+        assert!(text.contains("Match: {my-custom}* (types: hash, string)"));
+        assert!(text.contains("By type: hash 12, string 8"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_render_excluded_keys() {
+        let mut results = run(100).await;
+        assert!(
+            !render_notification_content("notification.j2", results.clone(), "*.j2")
+                .unwrap()
+                .contains("Excluded")
+        );
+        results[0].config.include = vec!["other:*".to_string()];
+        results[0].config.exclude = vec!["{my-custom}:config:*".to_string()];
+        results[0].excluded_keys = 3;
+        let text = render_notification_content("notification.j2", results, "*.j2").unwrap();
+        assert!(text.contains("Match: {my-custom}*, other:* except {my-custom}:config:*"));
+        assert!(text.contains("Excluded (number of keys): 3"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_render_incomplete() {
+        let results = run(2).await;
+        assert_eq!(status_color(&results), COLOR_INCOMPLETE);
+        let text = render_notification_content("notification.j2", results.clone(), "*.j2").unwrap();
+        assert!(text.contains(":warning:"));
+        assert!(
+            text.contains("Incomplete: the scan stopped after 2 batches"),
+            "{}",
+            text
+        );
+        let mut results = results;
+        results[0].incomplete_reason = Some(IncompleteReason::ReplicaWaitExceeded);
+        let text = render_notification_content("notification.j2", results, "*.j2").unwrap();
+        assert!(
+            text.contains("Incomplete: the replicas were still behind after 60000 ms"),
+            "{}",
+            text
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_skipped() {
+        let mut results = run(100).await;
+        results[0].status = ProcessingStatus::Skipped;
+        assert_eq!(status_color(&results), COLOR_SUCCESS);
+        let text = render_notification_content("notification.j2", results, "*.j2").unwrap();
+        assert!(text.contains("My Custom keys :fast_forward:"));
+        assert!(text.contains("Skipped: locked"));
+        assert!(!text.contains("Set expiration"));
+    }
+
+    #[tokio::test]
+    async fn test_render_grouped_by_target() {
+        let text = render_notification_content("notification.j2", run(100).await, "*.j2").unwrap();
+        assert!(!text.contains("*default*"));
+        let mut results = [run(100).await, run(100).await, run(100).await].concat();
+        results[0].config.target = Some("production".to_string());
+        results[1].config.target = Some("staging".to_string());
+        results[2].config.target = Some("production".to_string());
+        let text = render_notification_content("notification.j2", results, "*.j2").unwrap();
+        let production = text.find("*production*").unwrap();
+        let staging = text.find("*staging*").unwrap();
+        assert!(production < staging);
+        assert_eq!(
+            text[production..staging].matches("My Custom keys").count(),
+            2
+        );
+        assert_eq!(text[staging..].matches("My Custom keys").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_status_color() {
+        assert_eq!(status_color(&run(100).await), COLOR_SUCCESS);
+        assert_eq!(status_color(&[]), COLOR_SUCCESS);
+    }
+
+    #[tokio::test]
+    async fn test_missing_template_is_an_error() {
+        let err = render_notification_content("missing.j2", run(100).await, "*.j2").unwrap_err();
+        assert_eq!(err.exit_code(), 7);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::config::CleanupConfig;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the operations sent to Redis, `0` means no limit.
+///
+/// `keys_per_second` counts the keys returned by `SCAN`, every one of them gets a `TTL` and
+/// the ones without a TTL an `EXPIRE`; `scans_per_second` counts the `SCAN` calls.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub keys_per_second: u64,
+    pub scans_per_second: u64,
+}
+
+impl RateLimit {
+    pub fn new(keys_per_second: u64, scans_per_second: u64) -> RateLimit {
+        RateLimit {
+            keys_per_second,
+            scans_per_second,
+        }
+    }
+
+    /// The limits of a rule, `maxKeysPerSecond` and `maxScansPerSecond`.
+    pub fn for_config(conf: &CleanupConfig) -> RateLimit {
+        RateLimit::new(
+            conf.max_keys_per_second.unwrap_or(0),
+            conf.max_scans_per_second.unwrap_or(0),
+        )
+    }
+}
+
+/// Spaces out the operations so that they stay below `rate` per second.
+///
+/// An operation is never delayed by its own size, only by the ones before it: a batch is
+/// sent at once and the pause that pays for it comes before the next batch.
+#[derive(Debug)]
+struct Pacer {
+    rate: u64,
+    next: Mutex<Option<Instant>>,
+}
+
+impl Pacer {
+    fn new(rate: u64) -> Pacer {
+        Pacer {
+            rate,
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Books `amount` operations at `now`, returns how long they have to wait.
+    fn reserve(&self, amount: u64, now: Instant) -> Duration {
+        let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+        let start = next.map_or(now, |next| next.max(now));
+        *next = Some(start + Duration::from_secs_f64(amount as f64 / self.rate as f64));
+        start - now
+    }
+}
+
+/// The limits applied to a rule: its own and the global ones, which are shared by every
+/// rule of the [`Cleaner`](crate::Cleaner).
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    keys: Vec<Arc<Pacer>>,
+    scans: Vec<Arc<Pacer>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter::default().with(limit)
+    }
+
+    /// A limiter that also enforces `limit`, on top of the limits of `self`.
+    pub fn with(&self, limit: RateLimit) -> RateLimiter {
+        let mut limiter = self.clone();
+        if limit.keys_per_second > 0 {
+            limiter
+                .keys
+                .push(Arc::new(Pacer::new(limit.keys_per_second)));
+        }
+        if limit.scans_per_second > 0 {
+            limiter
+                .scans
+                .push(Arc::new(Pacer::new(limit.scans_per_second)));
+        }
+        limiter
+    }
+
+    pub fn is_limited(&self) -> bool {
+        !self.keys.is_empty() || !self.scans.is_empty()
+    }
+
+    /// The largest number of keys worth a single pipeline, a tenth of a second of the
+    /// strictest key limit, so a huge `batch` does not reach Redis as one burst.
+    pub fn key_chunk(&self) -> Option<usize> {
+        self.keys
+            .iter()
+            .map(|pacer| (pacer.rate / 10).max(1) as usize)
+            .min()
+    }
+
+    /// Waits until `keys` more keys are allowed, returns the pause.
+    pub async fn keys(&self, keys: usize) -> Duration {
+        pace(&self.keys, keys as u64).await
+    }
+
+    /// Waits until one more `SCAN` is allowed, returns the pause.
+    pub async fn scan(&self) -> Duration {
+        pace(&self.scans, 1).await
+    }
+}
+
+async fn pace(pacers: &[Arc<Pacer>], amount: u64) -> Duration {
+    let now = Instant::now();
+    let delay = pacers
+        .iter()
+        .map(|pacer| pacer.reserve(amount, now))
+        .max()
+        .unwrap_or_default();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+    delay
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pacer, RateLimit, RateLimiter};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_pacer_spaces_out_batches() {
+        let pacer = Pacer::new(100);
+        let now = Instant::now();
+        assert_eq!(pacer.reserve(50, now), Duration::ZERO);
+        assert_eq!(pacer.reserve(50, now), Duration::from_millis(500));
+        assert_eq!(
+            pacer.reserve(10, now + Duration::from_millis(200)),
+            Duration::from_millis(800)
+        );
+        // an idle pacer does not save up for a later burst
+        let later = now + Duration::from_secs(10);
+        assert_eq!(pacer.reserve(1000, later), Duration::ZERO);
+        assert_eq!(pacer.reserve(1, later), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_limiter_combines_rule_and_global_limits() {
+        let global = RateLimiter::new(RateLimit::new(1000, 0));
+        let limiter = global.with(RateLimit::new(200, 100));
+        assert_eq!(limiter.key_chunk(), Some(20));
+        assert_eq!(global.key_chunk(), Some(100));
+        assert_eq!(RateLimiter::default().key_chunk(), None);
+        assert_eq!(limiter.keys(20).await, Duration::ZERO);
+        // the global limit is shared, the keys of the rule used it too
+        let pause = global.keys(1).await;
+        assert!(pause > Duration::ZERO, "{:?}", pause);
+        let pause = limiter.keys(20).await;
+        assert!(pause > Duration::from_millis(50), "{:?}", pause);
+        assert_eq!(limiter.scan().await, Duration::ZERO);
+        assert!(limiter.scan().await > Duration::ZERO);
+        assert_eq!(RateLimiter::default().scan().await, Duration::ZERO);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::config::{CleanupConfig, KeyType};
+use crate::error::ErrorCategory;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProcessingStatus {
+    Success,
+    Incomplete,
+    Failed,
+    /// The rule did not run, another instance holds its lock.
+    Skipped,
+}
+
+/// Why a scan stopped before the end of the keyspace.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IncompleteReason {
+    /// The scan ran `maxIterations` batches.
+    MaxIterations,
+    /// The replicas were still behind after `maxReplicaWaitMs`.
+    ReplicaWaitExceeded,
+    /// The server was still overloaded after the `maxWaitMs` of the throttle.
+    ThrottleWaitExceeded,
+    /// Another instance took the lock of the rule over, the rule fails.
+    LockLost,
+}
+
+/// Time spent in the phases of a rule, in milliseconds.
+///
+/// With the `lua` engine `TTL` and `EXPIRE` run inside the script, so the whole script is
+/// counted as `scan_ms`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timings {
+    pub connect_ms: f64,
+    pub scan_ms: f64,
+    pub expire_ms: f64,
+    /// Pauses of the rate limits, of the throttling and the waits for lagging replicas.
+    pub throttle_ms: f64,
+    pub total_ms: f64,
+}
+
+/// Outcome of a rule on a single Redis node (one entry per primary in cluster mode).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeResult {
+    pub node: String,
+    /// The keys changed by the action of the rule, the sum of the counters below.
+    pub processed_keys: i64,
+    /// Keys without a TTL that got `ttlSeconds`.
+    pub ttl_added: i64,
+    /// Keys whose TTL was lowered to `maxTtlSeconds`.
+    pub ttl_shortened: i64,
+    /// Keys removed by the `delete` action.
+    pub deleted: i64,
+    /// Keys whose TTL was removed by the `persist` action.
+    pub persisted: i64,
+    /// The changed keys per type, only counted for the rules with `types`.
+    pub keys_by_type: BTreeMap<KeyType, i64>,
+    /// Keys returned by `SCAN` but left out by the `exclude` globs or the `regex`.
+    pub excluded_keys: i64,
+    pub iterations: i64,
+    pub error_msg: String,
+    pub error_kind: Option<ErrorCategory>,
+    pub resumed: bool,
+    pub completed: bool,
+    /// Why the scan stopped early, `None` if it completed or an error stopped it.
+    pub incomplete_reason: Option<IncompleteReason>,
+    pub timings: Timings,
+    /// How many operations were retried after a transient error.
+    pub retries: u32,
+}
+
+/// Outcome of a rule, aggregated over all of its nodes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProcessingResult {
+    pub config: CleanupConfig,
+    pub processed_keys: i64,
+    pub ttl_added: i64,
+    pub ttl_shortened: i64,
+    pub deleted: i64,
+    pub persisted: i64,
+    pub keys_by_type: BTreeMap<KeyType, i64>,
+    pub excluded_keys: i64,
+    pub iterations: i64,
+    pub error_msg: String,
+    pub error_kind: Option<ErrorCategory>,
+    pub execution_time: String,
+    pub timings: Timings,
+    /// How many operations were retried after a transient error, over all nodes.
+    pub retries: u32,
+    pub status: ProcessingStatus,
+    pub resumed: bool,
+    pub completed: bool,
+    /// Why the scan of the first incomplete node stopped early.
+    pub incomplete_reason: Option<IncompleteReason>,
+    pub nodes: Vec<NodeResult>,
+}
+
+/// The process exit code for a run: the category of the first failed rule wins over an
+/// incomplete scan (`2`), `1` is used for failures without a category.
+pub fn exit_code(results: &[ProcessingResult]) -> i32 {
+    if let Some(failed) = results
+        .iter()
+        .find(|res| res.status == ProcessingStatus::Failed)
+    {
+        return failed.error_kind.map(|kind| kind.exit_code()).unwrap_or(1);
+    }
+    if results
+        .iter()
+        .any(|res| res.status == ProcessingStatus::Incomplete)
+    {
+        return 2;
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exit_code, ProcessingResult, ProcessingStatus};
+    use crate::config::CleanupConfig;
+    use crate::error::ErrorCategory;
+
+    fn result(status: ProcessingStatus, error_kind: Option<ErrorCategory>) -> ProcessingResult {
+        ProcessingResult {
+            config: CleanupConfig::new("rule", "*", 60, 10),
+            processed_keys: 0,
+            ttl_added: 0,
+            ttl_shortened: 0,
+            deleted: 0,
+            persisted: 0,
+            keys_by_type: Default::default(),
+            excluded_keys: 0,
+            iterations: 0,
+            error_msg: String::new(),
+            error_kind,
+            execution_time: String::new(),
+            timings: Default::default(),
+            retries: 0,
+            status,
+            resumed: false,
+            completed: status != ProcessingStatus::Incomplete,
+            incomplete_reason: None,
+            nodes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let success = result(ProcessingStatus::Success, None);
+        let incomplete = result(ProcessingStatus::Incomplete, None);
+        let failed = result(ProcessingStatus::Failed, Some(ErrorCategory::Connection));
+        assert_eq!(exit_code(&[]), 0);
+        assert_eq!(exit_code(std::slice::from_ref(&success)), 0);
+        assert_eq!(exit_code(&[success.clone(), incomplete.clone()]), 2);
+        assert_eq!(exit_code(&[incomplete, failed, success]), 4);
+        assert_eq!(exit_code(&[result(ProcessingStatus::Failed, None)]), 1);
+        assert_eq!(exit_code(&[result(ProcessingStatus::Skipped, None)]), 0);
+    }
+}
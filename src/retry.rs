@@ -0,0 +1,194 @@
+use crate::error::CleanerError;
+use log::warn;
+use redis::{ErrorKind, RedisError};
+use std::collections::hash_map::RandomState;
+use std::fmt::Display;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// How transient Redis errors are retried: connection establishment and every batch
+/// (`SCAN`, `TTL`, `EXPIRE` or the script) are attempted again after an exponential backoff.
+///
+/// The backoff doubles from `initial_backoff` up to `max_backoff`, with a random jitter of
+/// up to half of it so the instances of a fleet do not retry in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// No retries, the first error fails the rule.
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, initial_backoff: Duration, max_backoff: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff,
+            max_backoff,
+        }
+    }
+
+    /// The pause before the retry number `retry` (starting at 1).
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+        let half = exponential / 2;
+        half + half.mul_f64(jitter())
+    }
+
+    /// Runs `operation` until it succeeds, fails with a non-transient error or runs out of
+    /// retries; `retries` is increased by every retry.
+    pub async fn run<T, E, F, Fut>(
+        &self,
+        what: &str,
+        retries: &mut u32,
+        mut operation: F,
+    ) -> Result<T, E>
+    where
+        E: Transient + Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempts = self.attempts(what);
+        loop {
+            match operation().await {
+                Ok(value) => return Ok(value),
+                Err(err) => attempts.failed(err, retries).await?,
+            }
+        }
+    }
+
+    /// The attempts of a single operation, for operations that borrow their state mutably.
+    pub fn attempts<'a>(&'a self, what: &'a str) -> Attempts<'a> {
+        Attempts {
+            policy: self,
+            what,
+            attempt: 0,
+        }
+    }
+}
+
+/// Counts the attempts of an operation, see [`RetryPolicy::attempts`].
+pub struct Attempts<'a> {
+    policy: &'a RetryPolicy,
+    what: &'a str,
+    attempt: u32,
+}
+
+impl Attempts<'_> {
+    /// Waits for the backoff if `err` is worth another attempt, returns it otherwise.
+    pub async fn failed<E: Transient + Display>(
+        &mut self,
+        err: E,
+        retries: &mut u32,
+    ) -> Result<(), E> {
+        if self.attempt >= self.policy.max_retries || !err.is_transient() {
+            return Err(err);
+        }
+        self.attempt += 1;
+        *retries += 1;
+        let backoff = self.policy.backoff(self.attempt);
+        warn!(
+            "{} failed ({}), retry {}/{} in {:?}",
+            self.what, err, self.attempt, self.policy.max_retries, backoff
+        );
+        tokio::time::sleep(backoff).await;
+        Ok(())
+    }
+}
+
+/// Errors that may go away by themselves: the server is loading its dataset, busy with a
+/// script, or temporarily unreachable.
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for RedisError {
+    fn is_transient(&self) -> bool {
+        if self.is_io_error() {
+            return true;
+        }
+        match self.kind() {
+            ErrorKind::BusyLoadingError | ErrorKind::TryAgain | ErrorKind::MasterDown => true,
+            _ => self.code() == Some("BUSY"),
+        }
+    }
+}
+
+impl Transient for CleanerError {
+    fn is_transient(&self) -> bool {
+        match self {
+            CleanerError::Connection(err) | CleanerError::Script(err) => err.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+/// A random number in `[0, 1)`, seeded by the random keys of the standard library.
+fn jitter() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryPolicy, Transient};
+    use redis::{ErrorKind, RedisError, RedisResult};
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff_is_exponential_with_jitter() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(100), Duration::from_millis(1000));
+        for (retry, base) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000)] {
+            let backoff = policy.backoff(retry);
+            assert!(backoff >= Duration::from_millis(base / 2), "{:?}", backoff);
+            assert!(backoff <= Duration::from_millis(base), "{:?}", backoff);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_transient_errors_are_retried() {
+        let policy = RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(1));
+        let mut retries = 0;
+        let mut calls = 0;
+        let result: RedisResult<i32> = policy
+            .run("SCAN", &mut retries, || {
+                calls += 1;
+                let calls = calls;
+                async move {
+                    match calls {
+                        1 | 2 => Err(RedisError::from((ErrorKind::BusyLoadingError, "LOADING"))),
+                        _ => Ok(calls),
+                    }
+                }
+            })
+            .await;
+        assert_eq!((result.unwrap(), retries), (3, 2));
+        let mut retries = 0;
+        let result: RedisResult<()> = policy
+            .run("SCAN", &mut retries, || async {
+                Err(RedisError::from((
+                    ErrorKind::AuthenticationFailed,
+                    "WRONGPASS",
+                )))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(retries, 0);
+        assert!(RedisError::from((ErrorKind::MasterDown, "MASTERDOWN")).is_transient());
+        assert!(!RedisError::from((ErrorKind::TypeError, "WRONGTYPE")).is_transient());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::config::CleanupConfig;
+use crate::error::CleanerError;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+/// The cron schedule of a rule in daemon mode, evaluated in the timezone of the rule.
+#[derive(Debug, Clone)]
+pub struct RuleSchedule {
+    schedule: Schedule,
+    timezone: Tz,
+}
+
+impl RuleSchedule {
+    /// Parses a cron expression with 5 fields (`minute hour day month weekday`, like crontab,
+    /// with Sunday as `0` or `7`) or 6-7 fields (with leading seconds and optional trailing
+    /// year, weekdays numbered by the cron crate from Sunday as `1`). The timezone is an IANA
+    /// name such as `Europe/Budapest`, `UTC` if not set.
+    pub fn parse(expression: &str, timezone: Option<&str>) -> Result<RuleSchedule, CleanerError> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let expression = match fields.as_slice() {
+            [minute, hour, day, month, weekday] => format!(
+                "0 {} {} {} {} {}",
+                minute,
+                hour,
+                day,
+                month,
+                crontab_weekdays(weekday)?
+            ),
+            _ => expression.to_string(),
+        };
+        let schedule = Schedule::from_str(&expression).map_err(|e| {
+            CleanerError::Config(format!("invalid schedule '{}': {}", expression, e))
+        })?;
+        let timezone = match timezone {
+            Some(name) => Tz::from_str(name)
+                .map_err(|e| CleanerError::Config(format!("invalid timezone '{}': {}", name, e)))?,
+            None => Tz::UTC,
+        };
+        Ok(RuleSchedule { schedule, timezone })
+    }
+
+    /// The schedule of a rule, `None` if the rule has no `schedule`.
+    pub fn for_config(conf: &CleanupConfig) -> Result<Option<RuleSchedule>, CleanerError> {
+        conf.schedule
+            .as_deref()
+            .map(|expression| {
+                RuleSchedule::parse(expression, conf.timezone.as_deref()).map_err(|err| match err {
+                    CleanerError::Config(msg) => {
+                        CleanerError::Config(format!("rule '{}': {}", conf.name, msg))
+                    }
+                    other => other,
+                })
+            })
+            .transpose()
+    }
+
+    /// The first scheduled time strictly after `time`.
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.schedule
+            .after(&time.with_timezone(&self.timezone))
+            .next()
+            .map(|next| next.with_timezone(&Utc))
+    }
+
+    /// The number of scheduled times in `(from, to]`.
+    pub fn count_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> usize {
+        self.schedule
+            .after(&from.with_timezone(&self.timezone))
+            .take_while(|next| next.with_timezone(&Utc) <= to)
+            .count()
+    }
+}
+
+/// Converts a crontab day-of-week field (`0`-`7`, Sunday is both `0` and `7`) to the numbering
+/// of the cron crate (`1`-`7`, from Sunday). Numbers, ranges and steps are expanded to a list
+/// of days, `*` and day names are kept as they are.
+fn crontab_weekdays(field: &str) -> Result<String, CleanerError> {
+    let invalid = || CleanerError::Config(format!("invalid day of week '{}'", field));
+    let mut days = BTreeSet::new();
+    let mut names = Vec::new();
+    for part in field.split(',') {
+        if part == "*" || part == "?" || part.chars().any(|c| c.is_ascii_alphabetic()) {
+            names.push(part.to_string());
+            continue;
+        }
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let number = |value: &str| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|day| *day <= 7)
+                .ok_or_else(invalid)
+        };
+        let (first, last) = if range == "*" {
+            (0, 6)
+        } else if let Some((first, last)) = range.split_once('-') {
+            (number(first)?, number(last)?)
+        } else {
+            let day = number(range)?;
+            (day, if step > 1 { 7 } else { day })
+        };
+        if first > last || step == 0 {
+            return Err(invalid());
+        }
+        days.extend((first..=last).step_by(step).map(|day| day % 7 + 1));
+    }
+    names.extend(days.iter().map(|day| day.to_string()));
+    Ok(names.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RuleSchedule;
+    use crate::config::CleanupConfig;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_crontab_expression_in_timezone() {
+        let schedule = RuleSchedule::parse("30 2 * * *", Some("Europe/Budapest")).unwrap();
+        // 02:30 in Budapest is 00:30 UTC during summer time
+        let now = Utc.with_ymd_and_hms(2023, 7, 1, 12, 0, 0).unwrap();
+        let next = schedule.next_after(now).unwrap();
+        assert_eq!(next, Utc.with_ymd_and_hms(2023, 7, 2, 0, 30, 0).unwrap());
+        let later = Utc.with_ymd_and_hms(2023, 7, 5, 0, 0, 0).unwrap();
+        assert_eq!(schedule.count_between(now, later), 3);
+    }
+
+    #[test]
+    fn test_crontab_weekdays() {
+        // 2023-07-01 is a Saturday
+        let now = Utc.with_ymd_and_hms(2023, 7, 1, 12, 0, 0).unwrap();
+        let week = Utc.with_ymd_and_hms(2023, 7, 8, 12, 0, 0).unwrap();
+        let workdays = RuleSchedule::parse("0 9 * * 1-5", None).unwrap();
+        let monday = Utc.with_ymd_and_hms(2023, 7, 3, 9, 0, 0).unwrap();
+        assert_eq!(workdays.next_after(now).unwrap(), monday);
+        assert_eq!(workdays.count_between(now, week), 5);
+        let sunday = Utc.with_ymd_and_hms(2023, 7, 2, 9, 0, 0).unwrap();
+        for expression in ["0 9 * * 0", "0 9 * * 7"] {
+            let schedule = RuleSchedule::parse(expression, None).unwrap();
+            assert_eq!(schedule.next_after(now).unwrap(), sunday);
+            assert_eq!(schedule.count_between(now, week), 1);
+        }
+        let weekend = RuleSchedule::parse("0 9 * * 6-7", None).unwrap();
+        assert_eq!(weekend.next_after(now).unwrap(), sunday);
+        assert_eq!(weekend.count_between(now, week), 2);
+        assert!(RuleSchedule::parse("0 9 * * 8", None).is_err());
+    }
+
+    #[test]
+    fn test_expression_with_seconds() {
+        let schedule = RuleSchedule::parse("*/15 * * * * *", None).unwrap();
+        let now = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 1).unwrap();
+        let next = schedule.next_after(now).unwrap();
+        assert_eq!(next, Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 15).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_schedule_is_a_config_error() {
+        let err = RuleSchedule::parse("every day", None).unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        let err = RuleSchedule::parse("0 * * * *", Some("Mars/Olympus")).unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        let conf = CleanupConfig {
+            schedule: Some("0 * * *".to_string()),
+            ..CleanupConfig::new("hourly", "*", 60, 10)
+        };
+        let err = RuleSchedule::for_config(&conf).unwrap_err();
+        assert!(err.to_string().contains("rule 'hourly'"));
+        let unscheduled = CleanupConfig::new("manual", "*", 60, 10);
+        assert!(RuleSchedule::for_config(&unscheduled).unwrap().is_none());
+    }
+}
@@ -0,0 +1,283 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// The `throttle` of a rule: server health thresholds above which the rule pauses its
+/// batches, until the server recovers.
+///
+/// The health is sampled between batches, at most every `sample_interval_ms`: the round
+/// trip of a `PING` and the `instantaneous_ops_per_sec`, `connected_clients` and replica
+/// `lag` fields of `INFO`. While a threshold is exceeded the pause doubles from
+/// `min_pause_ms` up to `max_pause_ms`; the rule stops once a wait lasted `max_wait_ms`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ThrottleConfig {
+    #[serde(default)]
+    pub max_latency_ms: Option<u64>,
+    #[serde(default)]
+    pub max_ops_per_sec: Option<u64>,
+    #[serde(default)]
+    pub max_connected_clients: Option<u64>,
+    /// The highest `lag` (in seconds) of the replicas of the node.
+    #[serde(default)]
+    pub max_replication_lag_seconds: Option<u64>,
+    #[serde(default = "default_sample_interval_ms")]
+    pub sample_interval_ms: u64,
+    #[serde(default = "default_min_pause_ms")]
+    pub min_pause_ms: u64,
+    #[serde(default = "default_max_pause_ms")]
+    pub max_pause_ms: u64,
+    /// How long the batches wait for the server to recover before the rule stops.
+    #[serde(default = "default_max_wait_ms")]
+    pub max_wait_ms: u64,
+}
+
+fn default_sample_interval_ms() -> u64 {
+    1000
+}
+
+fn default_min_pause_ms() -> u64 {
+    100
+}
+
+fn default_max_pause_ms() -> u64 {
+    10000
+}
+
+fn default_max_wait_ms() -> u64 {
+    60000
+}
+
+impl Default for ThrottleConfig {
+    /// No thresholds, the server is always healthy.
+    fn default() -> Self {
+        ThrottleConfig {
+            max_latency_ms: None,
+            max_ops_per_sec: None,
+            max_connected_clients: None,
+            max_replication_lag_seconds: None,
+            sample_interval_ms: default_sample_interval_ms(),
+            min_pause_ms: default_min_pause_ms(),
+            max_pause_ms: default_max_pause_ms(),
+            max_wait_ms: default_max_wait_ms(),
+        }
+    }
+}
+
+impl ThrottleConfig {
+    /// The first exceeded threshold, `None` if the server is healthy.
+    pub fn exceeded(&self, health: &Health) -> Option<String> {
+        let latency_ms = health.latency.as_millis() as u64;
+        let checks = [
+            ("PING latency (ms)", Some(latency_ms), self.max_latency_ms),
+            ("ops/sec", health.ops_per_sec, self.max_ops_per_sec),
+            (
+                "connected clients",
+                health.connected_clients,
+                self.max_connected_clients,
+            ),
+            (
+                "replication lag (s)",
+                health.replication_lag_seconds,
+                self.max_replication_lag_seconds,
+            ),
+        ];
+        checks
+            .into_iter()
+            .find_map(|(name, value, max)| match (value, max) {
+                (Some(value), Some(max)) if value > max => {
+                    Some(format!("{} {} above {}", name, value, max))
+                }
+                _ => None,
+            })
+    }
+}
+
+/// A sample of the server health, the fields missing from `INFO` are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+    pub latency: Duration,
+    pub ops_per_sec: Option<u64>,
+    pub connected_clients: Option<u64>,
+    pub replication_lag_seconds: Option<u64>,
+}
+
+impl Health {
+    /// Reads the output of `INFO`, `latency` is the measured `PING` round trip.
+    pub fn parse(info: &str, latency: Duration) -> Health {
+        let mut health = Health {
+            latency,
+            ..Health::default()
+        };
+        for line in info.lines() {
+            let (name, value) = match line.trim().split_once(':') {
+                Some(field) => field,
+                None => continue,
+            };
+            match name {
+                "instantaneous_ops_per_sec" => health.ops_per_sec = value.parse().ok(),
+                "connected_clients" => health.connected_clients = value.parse().ok(),
+                // slave0:ip=10.0.0.2,port=6379,state=online,offset=1234,lag=0
+                _ if name.starts_with("slave") && name[5..].parse::<u32>().is_ok() => {
+                    let lag = value
+                        .split(',')
+                        .find_map(|field| field.strip_prefix("lag="))
+                        .and_then(|lag| lag.parse::<u64>().ok());
+                    if let Some(lag) = lag {
+                        health.replication_lag_seconds =
+                            Some(health.replication_lag_seconds.unwrap_or(0).max(lag));
+                    }
+                }
+                _ => {}
+            }
+        }
+        health
+    }
+}
+
+/// How many bytes of the replication stream the slowest online replica is behind
+/// `master_repl_offset`, from the output of `INFO replication`; `None` without replicas.
+pub fn replica_lag_bytes(info: &str) -> Option<u64> {
+    let mut primary_offset = None;
+    let mut replica_offset: Option<u64> = None;
+    for line in info.lines() {
+        let (name, value) = match line.trim().split_once(':') {
+            Some(field) => field,
+            None => continue,
+        };
+        if name == "master_repl_offset" {
+            primary_offset = value.parse::<u64>().ok();
+        } else if name.starts_with("slave") && name[5..].parse::<u32>().is_ok() {
+            // A disconnected replica has to resync anyway, waiting for it would never end.
+            let fields: Vec<&str> = value.split(',').collect();
+            if !fields.contains(&"state=online") {
+                continue;
+            }
+            let offset = fields
+                .iter()
+                .find_map(|field| field.strip_prefix("offset="))
+                .and_then(|offset| offset.parse::<u64>().ok());
+            if let Some(offset) = offset {
+                replica_offset = Some(replica_offset.map_or(offset, |min| min.min(offset)));
+            }
+        }
+    }
+    Some(primary_offset?.saturating_sub(replica_offset?))
+}
+
+/// The throttling state of a rule on one node.
+#[derive(Debug)]
+pub struct Throttle<'a> {
+    config: &'a ThrottleConfig,
+    last_sample: Option<Instant>,
+    pause: Duration,
+}
+
+impl<'a> Throttle<'a> {
+    pub fn new(config: &'a ThrottleConfig) -> Throttle<'a> {
+        Throttle {
+            config,
+            last_sample: None,
+            pause: Duration::ZERO,
+        }
+    }
+
+    /// Whether the health has to be sampled before the next batch: the interval elapsed, or
+    /// the server was overloaded at the last sample.
+    pub fn is_due(&self, now: Instant) -> bool {
+        match self.last_sample {
+            Some(last) => {
+                !self.pause.is_zero()
+                    || now.duration_since(last)
+                        >= Duration::from_millis(self.config.sample_interval_ms)
+            }
+            None => true,
+        }
+    }
+
+    /// Records a sample, returns the exceeded threshold and the pause to take before the
+    /// next sample, `None` if the batches can go on.
+    pub fn observe(&mut self, health: &Health, now: Instant) -> Option<(String, Duration)> {
+        self.last_sample = Some(now);
+        match self.config.exceeded(health) {
+            Some(reason) => {
+                self.pause = match self.pause.is_zero() {
+                    true => Duration::from_millis(self.config.min_pause_ms),
+                    false => self.pause * 2,
+                }
+                .min(Duration::from_millis(self.config.max_pause_ms));
+                Some((reason, self.pause))
+            }
+            None => {
+                self.pause = Duration::ZERO;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replica_lag_bytes, Health, Throttle, ThrottleConfig};
+    use std::time::{Duration, Instant};
+
+    const INFO: &str = "# Clients\r\nconnected_clients:120\r\n# Stats\r\ninstantaneous_ops_per_sec:45000\r\n# Replication\r\nrole:master\r\nconnected_slaves:2\r\nslave0:ip=10.0.0.2,port=6379,state=online,offset=1234,lag=0\r\nslave1:ip=10.0.0.3,port=6379,state=online,offset=1200,lag=3\r\n";
+
+    #[test]
+    fn test_parse_info() {
+        let health = Health::parse(INFO, Duration::from_millis(2));
+        assert_eq!(
+            health,
+            Health {
+                latency: Duration::from_millis(2),
+                ops_per_sec: Some(45000),
+                connected_clients: Some(120),
+                replication_lag_seconds: Some(3),
+            }
+        );
+        assert_eq!(Health::parse("", Duration::ZERO), Health::default());
+    }
+
+    #[test]
+    fn test_replica_lag_bytes() {
+        let info = "# Replication\r\nrole:master\r\nconnected_slaves:3\r\nslave0:ip=10.0.0.2,port=6379,state=online,offset=1200,lag=0\r\nslave1:ip=10.0.0.3,port=6379,state=online,offset=900,lag=1\r\nslave2:ip=10.0.0.4,port=6379,state=wait_bgsave,offset=0,lag=0\r\nmaster_repl_offset:1234\r\n";
+        assert_eq!(replica_lag_bytes(info), Some(334));
+        assert_eq!(
+            replica_lag_bytes("role:master\r\nconnected_slaves:0\r\nmaster_repl_offset:1234\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_throttle_pauses_while_overloaded() {
+        let config = ThrottleConfig {
+            max_ops_per_sec: Some(40000),
+            max_replication_lag_seconds: Some(5),
+            min_pause_ms: 100,
+            max_pause_ms: 300,
+            ..ThrottleConfig::default()
+        };
+        let overloaded = Health::parse(INFO, Duration::from_millis(2));
+        let healthy = Health {
+            ops_per_sec: Some(1000),
+            ..overloaded.clone()
+        };
+        let now = Instant::now();
+        let mut throttle = Throttle::new(&config);
+        assert!(throttle.is_due(now));
+        let (reason, pause) = throttle.observe(&overloaded, now).unwrap();
+        assert_eq!(reason, "ops/sec 45000 above 40000");
+        assert_eq!(pause, Duration::from_millis(100));
+        assert!(throttle.is_due(now));
+        let pauses: Vec<Duration> = (0..2)
+            .map(|_| throttle.observe(&overloaded, now).unwrap().1)
+            .collect();
+        assert_eq!(
+            pauses,
+            [Duration::from_millis(200), Duration::from_millis(300)]
+        );
+        assert_eq!(throttle.observe(&healthy, now), None);
+        assert!(!throttle.is_due(now + Duration::from_millis(999)));
+        assert!(throttle.is_due(now + Duration::from_secs(1)));
+        assert_eq!(ThrottleConfig::default().exceeded(&overloaded), None);
+    }
+}
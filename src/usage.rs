@@ -0,0 +1,104 @@
+use crate::config::CleanupConfig;
+use crate::error::CleanerError;
+
+/// Selects keys by how they are used, from the `OBJECT` metrics the server keeps for its
+/// eviction policy: `minIdleSeconds` or `maxFrequency` of a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsageFilter {
+    /// Keys not read or written for at least this many seconds (`OBJECT IDLETIME`), only
+    /// tracked under the LRU (and non-LFU) policies.
+    MinIdle(i64),
+    /// Keys whose logarithmic access counter is at most this (`OBJECT FREQ`), only tracked
+    /// under the LFU policies.
+    MaxFrequency(i64),
+}
+
+impl UsageFilter {
+    /// The filter of a rule, `None` if it selects every key. A server tracks either the idle
+    /// time or the frequency, so a rule can not use both.
+    pub fn for_config(conf: &CleanupConfig) -> Result<Option<UsageFilter>, CleanerError> {
+        match (conf.min_idle_seconds, conf.max_frequency) {
+            (Some(_), Some(_)) => Err(CleanerError::Config(format!(
+                "rule '{}': minIdleSeconds and maxFrequency can not be used together, the server tracks only one of them",
+                conf.name
+            ))),
+            (Some(idle), None) => Ok(Some(UsageFilter::MinIdle(idle))),
+            (None, Some(frequency)) => Ok(Some(UsageFilter::MaxFrequency(frequency))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The `OBJECT` subcommand of the metric.
+    pub fn subcommand(&self) -> &'static str {
+        match self {
+            UsageFilter::MinIdle(_) => "IDLETIME",
+            UsageFilter::MaxFrequency(_) => "FREQ",
+        }
+    }
+
+    /// Whether a key with the metric `value` is selected, `None` is a key that is gone.
+    pub fn selects(&self, value: Option<i64>) -> bool {
+        match (self, value) {
+            (UsageFilter::MinIdle(min), Some(idle)) => idle >= *min,
+            (UsageFilter::MaxFrequency(max), Some(frequency)) => frequency <= *max,
+            (_, None) => false,
+        }
+    }
+
+    /// Fails if the metric is not tracked under the `maxmemory_policy` of the server.
+    pub fn check_policy(&self, policy: &str) -> Result<(), String> {
+        let lfu = policy.contains("lfu");
+        match self {
+            UsageFilter::MinIdle(_) if lfu => Err(format!(
+                "minIdleSeconds needs OBJECT IDLETIME, which is not tracked under the {} maxmemory-policy",
+                policy
+            )),
+            UsageFilter::MaxFrequency(_) if !lfu => Err(format!(
+                "maxFrequency needs OBJECT FREQ, which is only tracked under an LFU maxmemory-policy (the server uses {})",
+                policy
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The `maxmemory_policy` field of `INFO memory`.
+pub fn maxmemory_policy(info: &str) -> Option<&str> {
+    info.lines()
+        .find_map(|line| line.trim().strip_prefix("maxmemory_policy:"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{maxmemory_policy, UsageFilter};
+    use crate::config::CleanupConfig;
+
+    #[test]
+    fn test_filter_of_rule() {
+        let mut conf = CleanupConfig::new("rule", "*", 60, 10);
+        assert_eq!(UsageFilter::for_config(&conf).unwrap(), None);
+        conf.min_idle_seconds = Some(3600);
+        let idle = UsageFilter::for_config(&conf).unwrap().unwrap();
+        assert!(idle.selects(Some(3600)));
+        assert!(!idle.selects(Some(10)));
+        assert!(!idle.selects(None));
+        conf.max_frequency = Some(2);
+        let err = UsageFilter::for_config(&conf).unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+    }
+
+    #[test]
+    fn test_policy_check() {
+        let info = "# Memory\r\nused_memory:1024\r\nmaxmemory_policy:allkeys-lfu\r\n";
+        let policy = maxmemory_policy(info).unwrap();
+        assert_eq!(policy, "allkeys-lfu");
+        assert!(UsageFilter::MaxFrequency(1).check_policy(policy).is_ok());
+        let err = UsageFilter::MinIdle(60).check_policy(policy).unwrap_err();
+        assert!(err.contains("allkeys-lfu"), "{}", err);
+        assert!(UsageFilter::MinIdle(60).check_policy("noeviction").is_ok());
+        assert!(UsageFilter::MaxFrequency(1)
+            .check_policy("volatile-lru")
+            .is_err());
+        assert_eq!(maxmemory_policy("used_memory:1024\r\n"), None);
+    }
+}